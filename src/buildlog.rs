@@ -0,0 +1,173 @@
+//
+// buildlog.rs
+// Copyright (C) 2021 matthew <matthew@matthew-ubuntu>
+// Distributed under terms of the MIT license.
+//
+
+//! Persistent build log
+//!
+//! This is kept in `.latexmk.json` next to the intermediate files, and remembers what earlier
+//! runs saw, so later runs can skip work that modification times alone would repeat.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use serde::{Deserialize, Serialize};
+
+/// Name of the build log, in the directory it describes
+pub const FILE_NAME: &str = ".latexmk.json";
+
+/// Recipes run in parallel, so updates are serialized to avoid losing any
+static LOCK: Mutex<()> = Mutex::new(());
+
+/// Contents of the build log
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct BuildLog {
+    /// Hash of the part of each input a recipe reads, keyed by file name
+    #[serde(default)]
+    pub inputs: HashMap<String, u64>,
+    /// The files the engine recorded in each `.fls`, keyed by its file name
+    #[serde(default)]
+    pub recorded: HashMap<String, Recorded>,
+    /// Outputs whose last build failed, by file name
+    ///
+    /// The engine writes its output even when the document has errors, so the modification
+    /// times would say it's up to date
+    #[serde(default)]
+    pub failed: BTreeSet<String>,
+}
+
+/// Files the engine read & wrote during a build
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Recorded {
+    pub inputs: Vec<PathBuf>,
+    pub outputs: Vec<PathBuf>,
+}
+
+fn log_path(file: &Path) -> PathBuf {
+    file.with_file_name(FILE_NAME)
+}
+
+fn file_name(file: &Path) -> String {
+    file.file_name()
+        .map_or_else(String::new, |n| n.to_string_lossy().into_owned())
+}
+
+impl BuildLog {
+    /// Read the build log for the directory containing `file`
+    ///
+    /// A missing log is treated as empty. So is a corrupt one, which is reported, since it means
+    /// the next build may do more work than needed.
+    pub fn load(file: &Path) -> Self {
+        Self::read(file).unwrap_or_else(|e| {
+            crate::message!(
+                "{} ignoring corrupt build log {}: {}",
+                crate::util::yellow("Warning:"),
+                crate::util::display_path(&log_path(file)),
+                e
+            );
+            Self::default()
+        })
+    }
+
+    fn read(file: &Path) -> serde_json::Result<Self> {
+        match std::fs::read_to_string(log_path(file)) {
+            Ok(contents) => serde_json::from_str(&contents),
+            Err(_) => Ok(Self::default()),
+        }
+    }
+
+    /// Write the build log for the directory containing `file`
+    ///
+    /// It's written next to the log and renamed over it, so an interrupted write can't leave it
+    /// half written
+    pub fn save(&self, file: &Path) -> std::io::Result<()> {
+        let path = log_path(file);
+        let partial = path.with_file_name(format!("{}.partial", FILE_NAME));
+        std::fs::write(&partial, serde_json::to_string_pretty(self)?)?;
+        std::fs::rename(&partial, &path).inspect_err(|_| {
+            let _ = std::fs::remove_file(&partial);
+        })
+    }
+}
+
+/// The hash recorded for `input` by the last run
+pub fn input_hash(input: &Path) -> Option<u64> {
+    let _lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    BuildLog::load(input).inputs.get(&file_name(input)).copied()
+}
+
+/// Record `hash` for `input`, for the next run to compare against
+pub fn record_input_hash(input: &Path, hash: u64) -> std::io::Result<()> {
+    let _lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    // A corrupt log was already reported when it was read, and is replaced here
+    let mut log = BuildLog::read(input).unwrap_or_default();
+    log.inputs.insert(file_name(input), hash);
+    log.save(input)
+}
+
+/// Whether the last build of `output` failed
+pub fn failed(output: &Path) -> bool {
+    let _lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    BuildLog::load(output).failed.contains(&file_name(output))
+}
+
+/// Whether the last build of one of the outputs of the job at `job` failed, i.e. of a file named
+/// after it with any extension
+pub fn job_failed(job: &Path) -> bool {
+    let _lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let stem = job
+        .file_stem()
+        .map_or_else(String::new, |n| n.to_string_lossy().into_owned());
+    let prefix = format!("{}.", stem);
+    BuildLog::load(job)
+        .failed
+        .iter()
+        .any(|name| name.starts_with(&prefix))
+}
+
+/// Record whether the build of `output` succeeded
+pub fn record_outcome(output: &Path, success: bool) -> std::io::Result<()> {
+    let _lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let mut log = BuildLog::read(output).unwrap_or_default();
+    let changed = if success {
+        log.failed.remove(&file_name(output))
+    } else {
+        log.failed.insert(file_name(output))
+    };
+    // Most builds succeed, so this saves writing the log every time
+    if changed {
+        log.save(output)?;
+    }
+    Ok(())
+}
+
+/// Record the files listed in `fls`, returning the ones recorded by the previous build
+pub fn replace_recorded(fls: &Path, recorded: Recorded) -> std::io::Result<Option<Recorded>> {
+    let _lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let mut log = BuildLog::read(fls).unwrap_or_default();
+    let previous = log.recorded.insert(file_name(fls), recorded);
+    log.save(fls)?;
+    Ok(previous)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::TempDir;
+
+    #[test]
+    fn corrupt_log_is_replaced() {
+        let dir = TempDir::new("buildlog-test").unwrap();
+        let input = dir.path.join("paper.tex");
+        std::fs::write(dir.path.join(FILE_NAME), "{\"inputs\": {\"paper.tex\"").unwrap();
+        assert!(BuildLog::load(&input).inputs.is_empty());
+        // Recording into it starts over, instead of failing every time
+        record_input_hash(&input, 42).unwrap();
+        assert_eq!(input_hash(&input), Some(42));
+        assert!(!dir.path.join(format!("{}.partial", FILE_NAME)).exists());
+    }
+}
@@ -0,0 +1,149 @@
+//
+// cli.rs
+// Copyright (C) 2021 matthew <matthew@matthew-ubuntu>
+// Distributed under terms of the MIT license.
+//
+
+//! The command line tool, built on the library
+
+use std::{
+    io::{Read, Write},
+    path::Path,
+};
+
+use clap::Clap;
+
+use crate::{completions, message, util::TempDir, ExitCode, Options, OutFmt};
+
+/// Jobname TeX uses for documents read from the terminal
+const STDIN_NAME: &str = "texput.tex";
+
+/// Write the document on stdin to a temporary directory, if the file is `-`
+fn stdin_document(options: &mut Options) -> std::io::Result<Option<TempDir>> {
+    if !options.files.iter().any(|f| f == Path::new("-")) {
+        return Ok(None);
+    }
+    if options.files.len() > 1 {
+        eprintln!("Error: `-` can't be combined with other files");
+        ExitCode::Usage.exit();
+    }
+    if options.watch {
+        eprintln!("Error: --watch can't be used with `-`, since there's no file to watch");
+        ExitCode::Usage.exit();
+    }
+    let mut source = String::new();
+    std::io::stdin().read_to_string(&mut source)?;
+    let dir = TempDir::new("stdin")?;
+    std::fs::write(dir.path.join(STDIN_NAME), source)?;
+    options.files = vec![dir.path.join(STDIN_NAME)];
+    Ok(Some(dir))
+}
+
+/// Run the command line tool, with the arguments the process was started with
+pub fn main() -> std::io::Result<()> {
+    let mut options = match Options::try_parse() {
+        Ok(options) => options,
+        // Help & version are still printed by clap
+        Err(e) if e.use_stderr() => {
+            let _ = e.print();
+            ExitCode::Usage.exit();
+        }
+        Err(e) => e.exit(),
+    };
+    if let Some(dir) = &options.relative_to {
+        // The paths given in the options, and the documents found when none are given, are all
+        // relative to the current directory, so it's changed before any of them are used
+        if let Err(e) = std::env::set_current_dir(dir) {
+            eprintln!("Error: --relative-to {}: {}", options.display_path(dir), e);
+            ExitCode::Usage.exit();
+        }
+    }
+    if options.stdout_pdf {
+        if options.output_format() != OutFmt::Pdf {
+            eprintln!("Error: --stdout-pdf needs the output to be a pdf");
+            ExitCode::Usage.exit();
+        }
+        crate::util::messages_to_stderr(true);
+    }
+    if options.plan_json {
+        // So the output can be parsed
+        crate::util::messages_to_stderr(true);
+    }
+    let name = std::env::current_exe()?
+        .file_name()
+        .map_or("latexmk".into(), |f| f.to_string_lossy().into_owned());
+    if let Some(shell) = options.shell_completion {
+        if let Err(e) = completions::generate(shell, &name, &mut std::io::stdout()) {
+            eprintln!("Error: {}", e);
+            ExitCode::Usage.exit();
+        }
+        return Ok(());
+    }
+    if let Some(shell) = options.install_completions {
+        match completions::install(shell, &name) {
+            Ok(path) => message!("Wrote {}", options.display_path(&path)),
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                ExitCode::Failed.exit();
+            }
+        }
+        return Ok(());
+    }
+    if options.selftest {
+        if !crate::selftest::run()? {
+            ExitCode::Failed.exit();
+        }
+        return Ok(());
+    }
+    if !options.touch.is_empty() {
+        let now = std::time::SystemTime::now();
+        for file in options.touch.iter() {
+            if let Err(e) = crate::util::touch(file, now) {
+                eprintln!("Error: {}: {}", options.display_path(file), e);
+                ExitCode::Failed.exit();
+            }
+        }
+        return Ok(());
+    }
+    let stdin_dir = stdin_document(&mut options)?;
+    if options.watch {
+        let server = match options.serve.map(crate::serve::Server::start) {
+            Some(Ok(server)) => Some(server),
+            Some(Err(e)) => {
+                eprintln!("Error: --serve: {}", e);
+                ExitCode::Failed.exit();
+            }
+            None => None,
+        };
+        crate::watch::run(&options, server.as_ref());
+    }
+    let report = crate::build(&options);
+    if options.stdout_pdf {
+        if let Some(output) = report.outputs.first().filter(|_| report.success) {
+            let mut stdout = std::io::stdout();
+            stdout.write_all(&std::fs::read(output)?)?;
+            stdout.flush()?;
+        }
+    } else if stdin_dir.is_some() {
+        // The named copy comes first, if there is one
+        if let Some(output) = report.outputs.first() {
+            let dest = output.file_name().unwrap();
+            std::fs::copy(output, dest)?;
+            message!("Wrote {}", options.display_path(Path::new(dest)));
+        }
+    }
+    drop(stdin_dir);
+    for missing in report.missing.iter() {
+        message!(
+            "{} {} is still missing",
+            crate::util::yellow("Warning:"),
+            options.display_path(missing)
+        );
+    }
+    if !report.success {
+        for diagnostic in report.diagnostics.iter() {
+            eprintln!("{} {}", crate::util::red("Error:"), diagnostic);
+        }
+    }
+    ExitCode::of(&report).exit()
+}
@@ -0,0 +1,129 @@
+//
+// completions.rs
+// Copyright (C) 2021 matthew <matthew@matthew-ubuntu>
+// Distributed under terms of the MIT license.
+//
+
+//! Shell completion scripts
+
+use std::{
+    io::{Error, ErrorKind, Write},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use clap::IntoApp;
+use clap_generate::{
+    generators::{Bash, Elvish, Fish, PowerShell, Zsh},
+    Shell,
+};
+
+use crate::{message, util, Options};
+
+fn unsupported(shell: Shell) -> Error {
+    Error::new(
+        ErrorKind::InvalidInput,
+        format!(
+            "completions for {} aren't supported, supported shells are {}",
+            shell,
+            Shell::variants().join(", ")
+        ),
+    )
+}
+
+/// Write the completion script for `shell` to `out`, completing the program `name`
+pub fn generate(shell: Shell, name: &str, out: &mut dyn Write) -> std::io::Result<()> {
+    let app = &mut Options::into_app();
+    match shell {
+        Shell::Bash => clap_generate::generate::<Bash, _>(app, name, out),
+        Shell::Elvish => clap_generate::generate::<Elvish, _>(app, name, out),
+        Shell::Fish => clap_generate::generate::<Fish, _>(app, name, out),
+        Shell::PowerShell => clap_generate::generate::<PowerShell, _>(app, name, out),
+        Shell::Zsh => clap_generate::generate::<Zsh, _>(app, name, out),
+        _ => return Err(unsupported(shell)),
+    }
+    Ok(())
+}
+
+/// The shell the user logs in with, from `SHELL`
+pub fn detect_shell() -> Option<Shell> {
+    let shell = PathBuf::from(std::env::var_os("SHELL")?);
+    shell.file_name()?.to_str()?.parse().ok()
+}
+
+fn home() -> std::io::Result<PathBuf> {
+    std::env::var_os("HOME")
+        .map(PathBuf::from)
+        .ok_or_else(|| Error::new(ErrorKind::NotFound, "HOME isn't set"))
+}
+
+/// `var` if it's set to an absolute path, otherwise `default` in the home directory
+fn xdg_dir(var: &str, default: &str) -> std::io::Result<PathBuf> {
+    match std::env::var_os(var).map(PathBuf::from) {
+        Some(dir) if dir.is_absolute() => Ok(dir),
+        _ => Ok(home()?.join(default)),
+    }
+}
+
+/// The first directory in the user's `$fpath` inside their home directory, if any
+fn zsh_fpath(home: &Path) -> Option<PathBuf> {
+    let output = util::output(
+        Command::new("zsh")
+            .args(["-ic", "print -rl -- $fpath"])
+            .stdin(Stdio::null())
+            .stderr(Stdio::null()),
+    )
+    .ok()?;
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(PathBuf::from)
+        .find(|dir| dir.starts_with(home))
+}
+
+/// Where `shell` looks for the completions of the program `name`
+fn install_path(shell: Shell, name: &str) -> std::io::Result<PathBuf> {
+    match shell {
+        Shell::Bash => Ok(xdg_dir("XDG_DATA_HOME", ".local/share")?
+            .join("bash-completion/completions")
+            .join(name)),
+        Shell::Fish => Ok(xdg_dir("XDG_CONFIG_HOME", ".config")?
+            .join("fish/completions")
+            .join(format!("{}.fish", name))),
+        Shell::Zsh => {
+            let home = home()?;
+            let dir = zsh_fpath(&home).unwrap_or_else(|| {
+                message!("No directory in $fpath is in your home directory, using ~/.zfunc");
+                message!("Add `fpath=(~/.zfunc $fpath)` to your .zshrc, before `compinit`");
+                home.join(".zfunc")
+            });
+            Ok(dir.join(format!("_{}", name)))
+        }
+        _ => Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "don't know where {} completions go, use `--shell-completion {}` instead",
+                shell, shell
+            ),
+        )),
+    }
+}
+
+/// Write the completion script for `shell` where it's picked up automatically
+///
+/// Defaults to the shell from `SHELL`. Returns the path written to.
+pub fn install(shell: Option<Shell>, name: &str) -> std::io::Result<PathBuf> {
+    let shell = shell.or_else(detect_shell).ok_or_else(|| {
+        Error::new(
+            ErrorKind::NotFound,
+            "couldn't detect the shell, pass one to --install-completions",
+        )
+    })?;
+    let path = install_path(shell, name)?;
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let mut script = vec![];
+    generate(shell, name, &mut script)?;
+    std::fs::write(&path, script)?;
+    Ok(path)
+}
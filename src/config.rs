@@ -0,0 +1,482 @@
+//
+// config.rs
+// Copyright (C) 2021 matthew <matthew@matthew-ubuntu>
+// Distributed under terms of the MIT license.
+//
+
+//! Per document settings
+//!
+//! These are read from `<stem>.latexmk.toml` next to the document, e.g.
+//!
+//! ```toml
+//! engine = "xelatex"
+//! shell-escape = true
+//! latex-args = ["-halt-on-error"]
+//! ```
+//!
+//! The same settings can be given for every document in a `latexmk.toml` in the current
+//! directory, and for every project in `latexmk/config.toml` in the user's config directory.
+//! Settings given on the command line take precedence, then the document's, the project's and
+//! finally the user's.
+//!
+//! A `.latexmkrc` (or `latexmkrc`) in the current directory, as written for the Perl latexmk,
+//! is read as well, see [`Latexmkrc`].
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use serde::Deserialize;
+
+use crate::{latex, Options, OutFmt, RunLimit};
+
+/// Contents of a `<stem>.latexmk.toml`
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct DocumentConfig {
+    /// Same as `--engine`
+    pub engine: Option<String>,
+    /// Same as `--shell-escape`
+    pub shell_escape: Option<bool>,
+    /// Same as `--latex-args`
+    #[serde(default)]
+    pub latex_args: Vec<String>,
+    /// Same as `--max-runs`, e.g. `max-runs = { bbl = 2 }`
+    #[serde(default)]
+    pub max_runs: BTreeMap<String, usize>,
+}
+
+/// Where the settings for the document at `file` are kept
+pub fn path_for(file: &Path) -> PathBuf {
+    let stem = file.file_stem().map_or("", |s| s.to_str().unwrap_or(""));
+    file.with_file_name(format!("{}.latexmk.toml", stem))
+}
+
+/// The settings for every document in the current directory
+pub const PROJECT_FILE: &str = "latexmk.toml";
+
+/// Where the user's settings for every project are kept, if they have a home directory
+pub fn global_path() -> Option<PathBuf> {
+    let dir = match std::env::var_os("XDG_CONFIG_HOME").map(PathBuf::from) {
+        Some(dir) if dir.is_absolute() => dir,
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+    };
+    Some(dir.join("latexmk/config.toml"))
+}
+
+impl std::fmt::Display for DocumentConfig {
+    /// The settings that are set, like they're written in the file
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut settings = vec![];
+        if let Some(engine) = &self.engine {
+            settings.push(format!("engine = {:?}", engine));
+        }
+        if let Some(shell_escape) = self.shell_escape {
+            settings.push(format!("shell-escape = {}", shell_escape));
+        }
+        if !self.latex_args.is_empty() {
+            settings.push(format!("latex-args = {:?}", self.latex_args));
+        }
+        if !self.max_runs.is_empty() {
+            let limits: Vec<_> = self
+                .max_runs
+                .iter()
+                .map(|(recipe, runs)| format!("{} = {}", recipe, runs))
+                .collect();
+            settings.push(format!("max-runs = {{ {} }}", limits.join(", ")));
+        }
+        write!(f, "{}", settings.join(", "))
+    }
+}
+
+impl DocumentConfig {
+    /// Read the settings for the document at `file`, if it has any
+    pub fn load(file: &Path) -> Result<Option<Self>, String> {
+        Self::read(&path_for(file))
+    }
+
+    /// Read the settings in `path`, if it exists
+    pub fn read(path: &Path) -> Result<Option<Self>, String> {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(format!("{}: {}", crate::util::display_path(path), e)),
+        };
+        toml::from_str(&contents)
+            .map(Some)
+            .map_err(|e| format!("{}: {}", crate::util::display_path(path), e))
+    }
+
+    /// Fill in the settings this doesn't set from `other`, which takes lower precedence
+    pub fn merge(&mut self, other: &Self) {
+        if self.engine.is_none() {
+            self.engine = other.engine.clone();
+        }
+        if self.shell_escape.is_none() {
+            self.shell_escape = other.shell_escape;
+        }
+        if self.latex_args.is_empty() {
+            self.latex_args = other.latex_args.clone();
+        }
+        for (recipe, runs) in other.max_runs.iter() {
+            self.max_runs.entry(recipe.clone()).or_insert(*runs);
+        }
+    }
+
+    /// Fill in the settings `options` doesn't set itself
+    pub fn apply(&self, options: &mut Options) -> Result<(), String> {
+        if options.engine.is_none() {
+            if let Some(engine) = &self.engine {
+                options.engine = Some(engine.parse()?);
+            }
+        }
+        // A flag that isn't given can't be told apart from one turned off
+        if !options.shell_escape {
+            options.shell_escape = self.shell_escape.unwrap_or(false);
+        }
+        if options.latex_args.is_empty() {
+            options.latex_args = self.latex_args.clone();
+        }
+        for (recipe, runs) in self.max_runs.iter() {
+            if !options.max_runs.iter().any(|limit| limit.recipe == *recipe) {
+                options.max_runs.push(RunLimit {
+                    recipe: recipe.clone(),
+                    runs: *runs,
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The subset of a Perl latexmk rc file that's understood
+///
+/// Only simple assignments of these variables are read:
+///
+/// - `$pdf_mode`: 0 for a dvi, 1 for pdflatex, 2 for dvips & ps2pdf, 3 for dvipdfmx, 4 for
+///   lualatex and 5 for xelatex
+/// - `$bibtex_use`: 0 never runs bibtex, 1 keeps the `.bbl` when cleaning
+/// - `$clean_ext`: extra extensions clean removes, like `--clean-ext`
+/// - `@default_files`: the documents to build when none are given
+/// - `$out_dir`: like `--output-dir`
+/// - `$pdflatex`, `$xelatex`, `$lualatex` & `$latex`: for the command of the engine that's
+///   used, `$latex` for a dvi, its arguments become `--latex-args`, except the placeholders like
+///   `%O %S` and the flags the engine is always run with. The program `$pdflatex` runs becomes
+///   the engine, when none is selected otherwise. A command running some other program, like
+///   `internal mysub %O %S` or a full path, is ignored with a warning
+///
+/// Anything else, like a `sub` or a double quoted string using a variable, is ignored
+#[derive(Debug, Default)]
+pub struct Latexmkrc {
+    pub pdf_mode: Option<u32>,
+    pub bibtex_use: Option<u32>,
+    pub clean_ext: Vec<String>,
+    pub default_files: Vec<PathBuf>,
+    pub out_dir: Option<PathBuf>,
+    /// `(variable, command)`, for the engine commands that are set
+    pub commands: Vec<(String, String)>,
+    /// The lines that weren't understood, as `(line number, line)`
+    pub ignored: Vec<(usize, String)>,
+}
+
+/// Flags the engine is always run with, so an rc file's command setting them again is ignored
+///
+/// The ones taking a value may have it in the next argument
+const ENGINE_FLAGS: &[(&str, bool)] = &[
+    ("-interaction", true),
+    ("-synctex", true),
+    ("-output-directory", true),
+    ("-recorder", false),
+    ("-file-line-error", false),
+];
+
+/// The arguments in `words` to pass on to the engine, without the placeholders like `%O %S`
+/// and the [`ENGINE_FLAGS`]
+fn engine_args<'a>(words: impl Iterator<Item = &'a str>) -> Vec<String> {
+    let mut ret: Vec<String> = vec![];
+    let mut words = words.peekable();
+    while let Some(word) = words.next() {
+        let flag = word
+            .strip_prefix('-')
+            .filter(|w| w.starts_with('-'))
+            .unwrap_or(word);
+        let (name, value) = match flag.split_once('=') {
+            Some((name, value)) => (name, Some(value)),
+            None => (flag, None),
+        };
+        if let Some((_, takes_value)) = ENGINE_FLAGS.iter().find(|(f, _)| *f == name) {
+            if *takes_value && value.is_none() {
+                words.next_if(|w| !w.starts_with('-') && !w.starts_with('%'));
+            }
+        } else if !word.starts_with('%') && !ret.iter().any(|a| a == word) {
+            ret.push(word.to_string());
+        }
+    }
+    ret
+}
+
+/// Names of the rc files, in the order they're looked for
+const RC_FILES: &[&str] = &[".latexmkrc", "latexmkrc"];
+
+/// The quoted strings in `value`, or `None` if it isn't only made of them
+///
+/// Lists may also use `qw(a b)`
+fn rc_strings(value: &str) -> Option<Vec<String>> {
+    let value = value.trim();
+    if let Some(words) = value.strip_prefix("qw(").and_then(|w| w.strip_suffix(')')) {
+        return Some(words.split_whitespace().map(String::from).collect());
+    }
+    let value = value
+        .strip_prefix('(')
+        .and_then(|v| v.strip_suffix(')'))
+        .unwrap_or(value);
+    let mut ret = vec![];
+    let mut rest = value.trim();
+    while !rest.is_empty() {
+        let quote = rest.chars().next()?;
+        if quote != '\'' && quote != '"' {
+            return None;
+        }
+        let end = rest[1..].find(quote)? + 1;
+        let string = &rest[1..end];
+        // Interpolation would need Perl
+        if quote == '"' && (string.contains('$') || string.contains('@')) {
+            return None;
+        }
+        ret.push(string.to_string());
+        rest = rest[end + 1..].trim_start();
+        rest = rest.strip_prefix(',').unwrap_or(rest).trim_start();
+    }
+    Some(ret)
+}
+
+/// The text of `line` before a `#` comment
+fn rc_code(line: &str) -> &str {
+    let mut quote = None;
+    for (i, c) in line.char_indices() {
+        match (quote, c) {
+            (None, '#') => return &line[..i],
+            (None, '\'' | '"') => quote = Some(c),
+            (Some(q), c) if q == c => quote = None,
+            _ => (),
+        }
+    }
+    line
+}
+
+impl Latexmkrc {
+    /// Read the rc file in the current directory, if there is one
+    pub fn load() -> Result<Option<(PathBuf, Self)>, String> {
+        for name in RC_FILES {
+            let path = PathBuf::from(name);
+            match std::fs::read_to_string(&path) {
+                Ok(contents) => return Ok(Some((path, Self::parse(&contents)))),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+                Err(e) => return Err(format!("{}: {}", crate::util::display_path(&path), e)),
+            }
+        }
+        Ok(None)
+    }
+
+    /// Read the assignments in `contents`
+    pub fn parse(contents: &str) -> Self {
+        let mut ret = Self::default();
+        for (i, line) in contents.lines().enumerate() {
+            let code = rc_code(line).trim();
+            if code.is_empty() {
+                continue;
+            }
+            if !ret.assign(code) {
+                ret.ignored.push((i + 1, line.to_string()));
+            }
+        }
+        ret
+    }
+
+    /// Read the assignment `code`, returning whether it was understood
+    fn assign(&mut self, code: &str) -> bool {
+        let (name, value) = match code.strip_suffix(';').and_then(|c| c.split_once('=')) {
+            Some((name, value)) => (name.trim(), value.trim()),
+            None => return false,
+        };
+        let string = || match rc_strings(value)?.as_slice() {
+            [string] => Some(string.clone()),
+            _ => None,
+        };
+        match name {
+            "$pdf_mode" | "$bibtex_use" => {
+                let number = match value.parse() {
+                    Ok(number) => Some(number),
+                    Err(_) => return false,
+                };
+                if name == "$pdf_mode" {
+                    self.pdf_mode = number;
+                } else {
+                    self.bibtex_use = number;
+                }
+            }
+            "$out_dir" => match string() {
+                Some(dir) => self.out_dir = Some(dir.into()),
+                None => return false,
+            },
+            "$clean_ext" => match string() {
+                Some(exts) => self.clean_ext = exts.split_whitespace().map(String::from).collect(),
+                None => return false,
+            },
+            "@default_files" => match rc_strings(value) {
+                Some(files) => self.default_files = files.into_iter().map(PathBuf::from).collect(),
+                None => return false,
+            },
+            "$pdflatex" | "$xelatex" | "$lualatex" | "$latex" => match string() {
+                Some(command) => self.commands.push((name[1..].to_string(), command)),
+                None => return false,
+            },
+            _ => return false,
+        }
+        true
+    }
+
+    /// Fill in the settings `options` doesn't set itself, returning warnings about the ones
+    /// that can't be used
+    pub fn apply(&self, options: &mut Options) -> Result<Vec<String>, String> {
+        let format_given = options.outfmt.is_some() || options.dvi || options.dvi_to_pdf;
+        if let Some(mode) = self.pdf_mode.filter(|_| !format_given) {
+            match mode {
+                0 => options.dvi = true,
+                1 => (),
+                2 | 3 => {
+                    options.dvi_to_pdf = true;
+                    options.dvi_to_pdf_via = if mode == 2 {
+                        latex::DviToPdf::Dvips
+                    } else {
+                        latex::DviToPdf::Dvipdfmx
+                    };
+                }
+                4 if options.engine.is_none() => options.engine = Some(latex::Engine::Lualatex),
+                5 if options.engine.is_none() => options.engine = Some(latex::Engine::Xelatex),
+                4 | 5 => (),
+                _ => return Err(format!("unknown $pdf_mode {}", mode)),
+            }
+        }
+        match self.bibtex_use {
+            Some(0) if !options.max_runs.iter().any(|limit| limit.recipe == "bbl") => {
+                options.max_runs.push(RunLimit {
+                    recipe: "bbl".into(),
+                    runs: 0,
+                })
+            }
+            Some(1) => options.protect.push("bbl".into()),
+            _ => (),
+        }
+        for ext in self.clean_ext.iter() {
+            if !options.clean_ext.contains(ext) {
+                options.clean_ext.push(ext.clone());
+            }
+        }
+        if options.files.is_empty() {
+            options.files = self.default_files.clone();
+        }
+        if options.output_dir.is_none() {
+            options.output_dir = self.out_dir.clone();
+        }
+        let mut warnings = vec![];
+        // Only the command for the engine that runs, the last time it's assigned
+        let selected = match options.output_format() {
+            OutFmt::Pdf => Some(options.engine.map_or("pdflatex", |e| e.command())),
+            OutFmt::Dvi => Some("latex"),
+            OutFmt::Html => None,
+        };
+        let command = selected.and_then(|s| self.commands.iter().rev().find(|(n, _)| n == s));
+        if let Some((name, command)) = command {
+            let mut words = command.split_whitespace();
+            let program = words.next().unwrap_or("");
+            let runs = if name == "pdflatex" && options.engine.is_none() {
+                program.parse().map(|engine| options.engine = Some(engine))
+            } else if program == name {
+                Ok(())
+            } else {
+                Err(format!("expected it to run {}", name))
+            };
+            match runs {
+                Ok(()) if options.latex_args.is_empty() => {
+                    options.latex_args = engine_args(words);
+                }
+                Ok(()) => (),
+                Err(e) => warnings.push(format!("ignoring ${} = '{}': {}", name, command, e)),
+            }
+        }
+        Ok(warnings)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Clap;
+
+    /// `options` after applying the rc file `contents`, and the warnings about it
+    fn applied(contents: &str, args: &[&str]) -> (Options, Vec<String>) {
+        let mut options = Options::try_parse_from(["latexmk"].iter().chain(args)).unwrap();
+        let warnings = Latexmkrc::parse(contents).apply(&mut options).unwrap();
+        (options, warnings)
+    }
+
+    #[test]
+    fn only_the_selected_engine_command() {
+        let rc = "$pdflatex = 'pdflatex -halt-on-error %O %S';\n\
+                  $xelatex = 'xelatex -8bit %O %S';\n\
+                  $latex = 'latex -src-specials %O %S';\n";
+        let (options, warnings) = applied(rc, &[]);
+        assert_eq!(options.latex_args, vec!["-halt-on-error"]);
+        assert!(warnings.is_empty());
+        assert_eq!(
+            applied(rc, &["--engine", "xelatex"]).0.latex_args,
+            vec!["-8bit"]
+        );
+        assert_eq!(applied(rc, &["--dvi"]).0.latex_args, vec!["-src-specials"]);
+        // The last assignment is the one Perl keeps
+        let rc = "$pdflatex = 'pdflatex -a %O %S';\n$pdflatex = 'pdflatex -b %O %S';\n";
+        assert_eq!(applied(rc, &[]).0.latex_args, vec!["-b"]);
+    }
+
+    #[test]
+    fn engine_flags_are_dropped() {
+        let rc = "$pdflatex = 'pdflatex -interaction=nonstopmode -synctex 1 -recorder \
+                  --file-line-error -output-directory build -halt-on-error %O %S';\n";
+        assert_eq!(applied(rc, &[]).0.latex_args, vec!["-halt-on-error"]);
+    }
+
+    #[test]
+    fn pdflatex_selects_engine() {
+        let rc = "$pdflatex = 'lualatex -shell-restricted %O %S';\n";
+        let (options, _) = applied(rc, &[]);
+        assert_eq!(options.engine, Some(latex::Engine::Lualatex));
+        assert_eq!(options.latex_args, vec!["-shell-restricted"]);
+        // Not when another is given
+        let (options, warnings) = applied(rc, &["--engine", "pdflatex"]);
+        assert_eq!(options.engine, Some(latex::Engine::Pdflatex));
+        assert!(options.latex_args.is_empty());
+        assert_eq!(warnings.len(), 1);
+    }
+
+    #[test]
+    fn other_programs_are_skipped() {
+        for rc in [
+            "$pdflatex = 'internal mysub %O %S';\n$bibtex_use = 1;\n",
+            "$pdflatex = '/usr/bin/pdflatex -halt-on-error %O %S';\n$bibtex_use = 1;\n",
+        ] {
+            let (options, warnings) = applied(rc, &[]);
+            assert_eq!(options.engine, None);
+            assert!(options.latex_args.is_empty());
+            assert_eq!(warnings.len(), 1, "{:?}", warnings);
+            assert!(
+                warnings[0].starts_with("ignoring $pdflatex = "),
+                "{}",
+                warnings[0]
+            );
+            // The rest of the file is still used
+            assert_eq!(options.protect, vec!["bbl"]);
+        }
+    }
+}
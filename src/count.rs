@@ -0,0 +1,93 @@
+//
+// count.rs
+// Copyright (C) 2021 matthew <matthew@matthew-ubuntu>
+// Distributed under terms of the MIT license.
+//
+
+//! Word counts, from texcount
+
+use std::{
+    io::{Error, ErrorKind},
+    path::Path,
+    process::Command,
+};
+
+use crate::{recipe::ToolNotFound, util};
+
+/// Totals texcount reports for a document and the files it includes
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Count {
+    /// Words in the body text
+    pub text: u64,
+    /// Words in section headings
+    pub headers: u64,
+    /// Words in figure & table captions
+    pub captions: u64,
+    /// Characters in the body text, headings & captions
+    pub chars: u64,
+}
+
+impl Count {
+    /// All the counted words
+    pub fn words(&self) -> u64 {
+        self.text + self.headers + self.captions
+    }
+
+    /// Add the totals in texcount's output to `self`
+    fn parse(&mut self, output: &str) {
+        for line in output.lines() {
+            let (key, value) = match line.split_once(':') {
+                Some((key, value)) => (key.trim(), value.trim()),
+                None => continue,
+            };
+            let value: u64 = match value.parse() {
+                Ok(value) => value,
+                Err(_) => continue,
+            };
+            match key {
+                "Words in text" => self.text += value,
+                "Words in headers" => self.headers += value,
+                "Words in float captions" => self.captions += value,
+                "Letters in text" | "Letters in headers" | "Letters in captions" => {
+                    self.chars += value
+                }
+                _ => (),
+            }
+        }
+    }
+}
+
+fn texcount(file: &Path, chars: bool) -> std::io::Result<String> {
+    let name = file.file_name().unwrap_or_default();
+    let mut cmd = Command::new("texcount");
+    cmd.args(["-inc", "-total"]);
+    if chars {
+        cmd.arg("-char");
+    }
+    cmd.arg(name);
+    if let Some(dir) = file.parent().filter(|p| !p.as_os_str().is_empty()) {
+        cmd.current_dir(dir);
+    }
+    let output = util::output(&mut cmd).map_err(|e| match e.kind() {
+        ErrorKind::NotFound => Error::new(ErrorKind::NotFound, ToolNotFound("texcount".into())),
+        _ => e,
+    })?;
+    if !output.status.success() {
+        return Err(Error::other(format!(
+            "texcount failed on {}: {}",
+            crate::util::display_path(file),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Count the words in the document at `file`, following `\input` and `\include`
+///
+/// texcount counts either words or characters, so it's run once for each
+pub fn count(file: &Path) -> std::io::Result<Count> {
+    let mut ret = Count::default();
+    ret.parse(&texcount(file, false)?);
+    ret.parse(&texcount(file, true)?);
+    Ok(ret)
+}
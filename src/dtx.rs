@@ -0,0 +1,58 @@
+//
+// dtx.rs
+// Copyright (C) 2021 matthew <matthew@matthew-ubuntu>
+// Distributed under terms of the MIT license.
+//
+
+//! Documentation of packages, built from their `.dtx` source
+//!
+//! The engine runs on the `.dtx` itself. Its index and change history are sorted by makeindex,
+//! with the `gind.ist` and `gglo.ist` styles from the doc package, instead of the usual styles.
+
+use std::{collections::HashMap, path::Path};
+
+use crate::{recipe::Recipe, util};
+
+/// Extensions of the sources the engine builds, besides `.tex`
+const SOURCE_EXTS: &[&str] = &["dtx", "ltx"];
+
+/// The extension of `path`, if it's a `.dtx` or `.ltx` source
+pub fn source_ext(path: &Path) -> Option<&'static str> {
+    let ext = path.extension()?;
+    SOURCE_EXTS.iter().copied().find(|e| ext == *e)
+}
+
+/// Whether `path` is a source the engine builds
+pub fn is_document(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "tex") || source_ext(path).is_some()
+}
+
+/// Add the recipes for the index & change history of a `.dtx`
+///
+/// The change history replaces the makeglossaries recipe, which would build from the same `.glo`
+pub fn register(map: &mut HashMap<String, Recipe>) {
+    map.insert(
+        "ind".into(),
+        Recipe {
+            uses: "idx",
+            generated: &["ilg"],
+            script: "makeindex -s gind.ist %N".into(),
+            // Rewritten on every pass, usually with the same entries
+            input_hash: Some(util::hash_file),
+            requires: &["makeindex"],
+            ..Recipe::default()
+        },
+    );
+    map.insert(
+        "gls".into(),
+        Recipe {
+            uses: "glo",
+            generated: &["glg"],
+            script: "makeindex -s gglo.ist -o %O -t %N.glg %I".into(),
+            // Rewritten on every pass, usually with the same entries
+            input_hash: Some(util::hash_file),
+            requires: &["makeindex"],
+            ..Recipe::default()
+        },
+    );
+}
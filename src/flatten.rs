@@ -0,0 +1,204 @@
+//
+// flatten.rs
+// Copyright (C) 2021 matthew <matthew@matthew-ubuntu>
+// Distributed under terms of the MIT license.
+//
+
+//! Merging a document and the files it includes into one `.tex`, for `--flatten`
+//!
+//! Publishers often want a single source file. `\input` (with or without braces) and
+//! `\include` are replaced by the contents of the file, as long as it's found relative to the
+//! document. Anything else, like `\input{tikz}` loading a package, is left alone.
+
+use std::{
+    io::Error,
+    path::{Path, PathBuf},
+};
+
+use crate::{message, scan, util};
+
+/// Offset of the `%` starting a comment in `line`, or its length if there's none
+fn comment_start(line: &str) -> usize {
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '\\' => escaped = !escaped,
+            '%' if !escaped => return i,
+            _ => escaped = false,
+        }
+    }
+    line.len()
+}
+
+/// The file name given to `\input` or `\include` at the start of `rest`, and what follows it
+///
+/// `\input` also takes a name without braces, ended by a space
+fn include_argument<'a>(command: &str, rest: &'a str) -> Option<(&'a str, &'a str)> {
+    if let Some(arg) = rest.trim_start().strip_prefix('{') {
+        let end = arg.find('}')?;
+        return Some((arg[..end].trim(), &arg[end + 1..]));
+    }
+    if command != "input" || !rest.starts_with([' ', '\t']) {
+        return None;
+    }
+    let arg = rest.trim_start();
+    let end = arg
+        .find(|c: char| c.is_whitespace() || c == '}' || c == '\\')
+        .unwrap_or(arg.len());
+    Some((&arg[..end], &arg[end..])).filter(|(name, _)| !name.is_empty())
+}
+
+/// Append `code`, a line without its comment, to `out`, inlining the files it includes
+fn flatten_code(
+    code: &str,
+    base: &Path,
+    out: &mut String,
+    stack: &mut Vec<PathBuf>,
+) -> std::io::Result<()> {
+    let mut cur = code;
+    while let Some(start) = cur.find('\\') {
+        let after = &cur[start + 1..];
+        let len = after
+            .find(|c: char| !c.is_ascii_alphabetic())
+            .unwrap_or(after.len());
+        let command = &after[..len];
+        let included = match command {
+            "input" | "include" => include_argument(command, &after[len..]),
+            _ => None,
+        };
+        let (name, rest) = match included {
+            Some(found) => found,
+            None => {
+                // Skip over the command, or the escaped character
+                let skip = start + 1 + len.max(after.chars().next().map_or(0, char::len_utf8));
+                out.push_str(&cur[..skip]);
+                cur = &cur[skip..];
+                continue;
+            }
+        };
+        let path = util::normalize(&scan::resolve_input(base, name));
+        if !path.is_file() {
+            // Probably from the TeX distribution
+            out.push_str(&cur[..cur.len() - rest.len()]);
+            cur = rest;
+            continue;
+        }
+        out.push_str(&cur[..start]);
+        let mut contents = String::new();
+        flatten_file(&path, base, &mut contents, stack)?;
+        // The end of the file's last line is a space, like the end of the `\input` line is
+        let contents = contents.strip_suffix('\n').unwrap_or(&contents);
+        if command == "include" {
+            out.push_str("\\clearpage\n");
+            out.push_str(contents);
+            out.push_str("\n\\clearpage");
+        } else {
+            out.push_str(contents);
+        }
+        if !rest.is_empty() && !rest.starts_with(char::is_whitespace) {
+            out.push('\n');
+        }
+        cur = rest;
+    }
+    out.push_str(cur);
+    Ok(())
+}
+
+/// Append the contents of `path` to `out`, inlining the files it includes
+///
+/// Paths in included files are still relative to `base`, the main document's directory
+fn flatten_file(
+    path: &Path,
+    base: &Path,
+    out: &mut String,
+    stack: &mut Vec<PathBuf>,
+) -> std::io::Result<()> {
+    let key = path.canonicalize()?;
+    if stack.contains(&key) {
+        return Err(Error::other(format!(
+            "{} includes itself",
+            util::display_path(path)
+        )));
+    }
+    let source = std::fs::read_to_string(path)?;
+    stack.push(key);
+    for line in source.split_inclusive('\n') {
+        let (code, comment) = line.split_at(comment_start(line));
+        // The rest of the file is never read by TeX, and the main document mustn't stop there
+        if code.trim_start().starts_with("\\endinput") {
+            break;
+        }
+        flatten_code(code, base, out, stack)?;
+        out.push_str(comment);
+    }
+    stack.pop();
+    Ok(())
+}
+
+/// The file `\includegraphics{name}` refers to, relative to `base`
+fn find_graphic(base: &Path, name: &str) -> Option<PathBuf> {
+    let path = base.join(name);
+    if path.is_file() {
+        return Some(path);
+    }
+    scan::GRAPHICS_EXTS
+        .iter()
+        .map(|ext| base.join(format!("{}.{}", name, ext)))
+        .find(|p| p.is_file())
+}
+
+/// Copy the images used by `source` from `base` to the same place relative to `dest`
+fn copy_graphics(source: &str, base: &Path, dest: &Path) -> std::io::Result<()> {
+    for name in scan::graphics(source) {
+        let relative = Path::new(&name);
+        // Those would have to be renamed to fit next to the output
+        if relative.is_absolute() || relative.components().any(|c| c.as_os_str() == "..") {
+            message!(
+                "{} not copying {}, since it's outside the document's directory",
+                util::yellow("Warning:"),
+                name
+            );
+            continue;
+        }
+        let from = match find_graphic(base, &name) {
+            Some(from) => from,
+            None => {
+                message!("{} {} not found", util::yellow("Warning:"), name);
+                continue;
+            }
+        };
+        let to = dest.join(from.strip_prefix(base).unwrap_or(&from));
+        if from.canonicalize().ok() == to.canonicalize().ok() {
+            continue;
+        }
+        if let Some(parent) = to.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        util::copy_file(&from, &to)?;
+    }
+    Ok(())
+}
+
+/// Write the document at `file`, with the files it includes inlined, to `output`
+///
+/// With `graphics`, the images it uses are copied next to `output` too
+pub fn flatten(file: &Path, output: &Path, graphics: bool) -> std::io::Result<()> {
+    let base = file.parent().unwrap_or_else(|| Path::new(""));
+    let mut flattened = String::new();
+    flatten_file(file, base, &mut flattened, &mut vec![])?;
+    if output.canonicalize().ok() == file.canonicalize().ok() {
+        return Err(Error::other("--flatten would overwrite the document"));
+    }
+    if !flattened.ends_with('\n') {
+        flattened.push('\n');
+    }
+    if let Some(parent) = output.parent().filter(|p| !p.as_os_str().is_empty()) {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(output, &flattened)?;
+    if graphics {
+        let dest = output.parent().unwrap_or_else(|| Path::new(""));
+        copy_graphics(&flattened, base, dest)?;
+    }
+    Ok(())
+}
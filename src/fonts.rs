@@ -0,0 +1,31 @@
+//
+// fonts.rs
+// Copyright (C) 2021 matthew <matthew@matthew-ubuntu>
+// Distributed under terms of the MIT license.
+//
+
+//! Characters the fonts of a document don't have
+//!
+//! TeX leaves these out of the output, and only mentions it in the log, as
+//! `Missing character: There is no → (U+2192) in font cmr10!`
+
+use std::collections::{BTreeMap, BTreeSet};
+
+/// The characters `log` says were missing, by font
+pub fn missing_characters(log: &str) -> BTreeMap<String, BTreeSet<String>> {
+    let mut ret: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+    for rest in log.split("Missing character: There is no ").skip(1) {
+        let (character, rest) = match rest.split_once(" in font ") {
+            Some(found) => found,
+            None => continue,
+        };
+        let font = match rest.split_once('!') {
+            Some((font, _)) => font.trim(),
+            None => continue,
+        };
+        ret.entry(font.to_string())
+            .or_default()
+            .insert(character.trim().to_string());
+    }
+    ret
+}
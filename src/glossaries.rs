@@ -0,0 +1,74 @@
+//
+// glossaries.rs
+// Copyright (C) 2021 matthew <matthew@matthew-ubuntu>
+// Distributed under terms of the MIT license.
+//
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    recipe::{parse_fls, Recipe, RecipeProvider},
+    util::{self, shell_quote},
+    Options,
+};
+
+/// Glossaries recipe, running makeglossaries
+pub struct Glossaries;
+
+impl RecipeProvider for Glossaries {
+    fn register(&self, options: &Options, map: &mut HashMap<String, Recipe>) {
+        let mut script = String::from("makeglossaries");
+        let mut extra_files = vec![];
+        if let Some(style) = &options.glossary_style {
+            // The script runs next to the `.glo`
+            let style = style.canonicalize().unwrap_or_else(|_| style.clone());
+            script.push_str(" -s ");
+            script.push_str(&shell_quote(&style.display().to_string()).replace('%', "%%"));
+            extra_files.push(style);
+        }
+        script.push_str(" %N");
+        map.insert(
+            "gls".into(),
+            Recipe {
+                uses: "glo",
+                generated: &["glg", "alg"],
+                script: script.into(),
+                extra_deps: if options.glossary_style.is_some() {
+                    None
+                } else {
+                    Some(style_deps)
+                },
+                extra_files,
+                // The acronym list, with the `acronym` package option
+                produces: &["acr"],
+                // The `.glo` is rewritten on every pass, usually with the same entries
+                input_hash: Some(util::hash_file),
+                requires: &["makeglossaries"],
+                ..Recipe::default()
+            },
+        );
+    }
+}
+
+/// The style file the document set with `\setStyleFile`, from `\@istfilename` in the `.aux`
+///
+/// Unless the document uses `\noist`, glossaries writes the style itself on every run, so it's
+/// only a dependency if the engine didn't record writing it
+fn style_deps(glo: &Path) -> Vec<PathBuf> {
+    let contents = std::fs::read_to_string(glo.with_extension("aux")).unwrap_or_default();
+    let written = parse_fls(&glo.with_extension("fls"))
+        .map(|fls| fls.output)
+        .unwrap_or_default();
+    contents
+        .lines()
+        .filter_map(|line| line.strip_prefix("\\@istfilename{")?.strip_suffix('}'))
+        .map(|name| glo.with_file_name(name.trim()))
+        .filter(|style| {
+            let style = style.canonicalize().unwrap_or_else(|_| style.clone());
+            !written.contains(&style)
+        })
+        .collect()
+}
@@ -0,0 +1,73 @@
+//
+// gnuplot.rs
+// Copyright (C) 2021 matthew <matthew@matthew-ubuntu>
+// Distributed under terms of the MIT license.
+//
+
+//! gnuplottex figures
+//!
+//! Without shell escape, gnuplottex only writes the `.gnuplot` scripts, and includes the figures
+//! they produce on the next pass. The scripts aren't read by latex, so they're found from the
+//! outputs of the last pass instead of going through the recipe map.
+
+use std::path::{Path, PathBuf};
+
+use crate::{
+    recipe::{Deps, Recipe},
+    util::{hash_file, shell_quote},
+    Options,
+};
+
+/// Extensions gnuplottex figures can have, depending on the terminal
+const FIGURE_EXTS: &[&str] = &["tex", "eps", "pdf", "png"];
+
+fn recipe(options: &Options) -> Recipe {
+    let mut script = String::from("gnuplot");
+    for arg in options.gnuplot_args.iter() {
+        script.push(' ');
+        script.push_str(&shell_quote(arg));
+    }
+    script.push_str(" %I");
+    Recipe {
+        uses: "gnuplot",
+        script: script.into(),
+        requires: &["gnuplot"],
+        ..Recipe::default()
+    }
+}
+
+/// The figure `script` produces, which is a `.tex` unless another kind already exists
+fn figure(script: &Path) -> PathBuf {
+    FIGURE_EXTS
+        .iter()
+        .map(|ext| script.with_extension(ext))
+        .find(|f| f.exists())
+        .unwrap_or_else(|| script.with_extension("tex"))
+}
+
+/// Run the gnuplot scripts among `outputs` that are newer than their figures
+///
+/// Returns whether any figures changed, in which case latex needs another pass
+pub fn run_scripts(
+    options: &Options,
+    outputs: &[PathBuf],
+    deps: &mut Deps,
+) -> std::io::Result<bool> {
+    let recipe = recipe(options);
+    let mut ran = false;
+    for script in outputs
+        .iter()
+        .filter(|p| p.extension().is_some_and(|e| e == "gnuplot"))
+    {
+        let figure = figure(script);
+        let ext = figure.extension().and_then(|e| e.to_str()).unwrap_or("tex");
+        // gnuplottex rewrites the scripts on every pass, so only a different figure needs a rerun
+        let before = hash_file(&figure);
+        recipe.run_for(&figure, ext, deps)?;
+        deps.add_output(figure.clone());
+        if hash_file(&figure) != before {
+            ran = true;
+        }
+    }
+    Ok(ran)
+}
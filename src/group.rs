@@ -0,0 +1,170 @@
+//
+// group.rs
+// Copyright (C) 2021 matthew <matthew@matthew-ubuntu>
+// Distributed under terms of the MIT license.
+//
+
+//! Sorting the files in the output directory by type, for `--group-output`
+//!
+//! Logs are moved to `logs/` and the other intermediate files to `aux/` once the build is done,
+//! leaving the final output at the top. They're moved back before the next build, so it can
+//! still tell what's up to date.
+
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+};
+
+/// The subdirectories files are sorted into
+const LOGS_DIR: &str = "logs";
+const AUX_DIR: &str = "aux";
+
+/// Whether the file `name` ends with one of `exts`
+fn has_ext(name: &str, exts: &[&str]) -> bool {
+    exts.iter().any(|ext| name.ends_with(&format!(".{}", ext)))
+}
+
+/// Move the files sorted by the last build back into `dir`
+pub fn restore(dir: &Path) -> std::io::Result<()> {
+    for sub in [AUX_DIR, LOGS_DIR] {
+        let entries = match dir.join(sub).read_dir() {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        for entry in entries {
+            let entry = entry?;
+            if entry.file_type()?.is_file() {
+                std::fs::rename(entry.path(), dir.join(entry.file_name()))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Move the files of `outputs` directly in `dir` into its subdirectories, except the ones
+/// ending with an extension in `keep`
+///
+/// Files ending with an extension in `logs` go to `logs/`, the rest to `aux/`. `outputs` is
+/// updated with where they were moved, so clean still finds them.
+pub fn sort(
+    dir: &Path,
+    outputs: &mut HashSet<PathBuf>,
+    keep: &[&str],
+    logs: &[&str],
+) -> std::io::Result<()> {
+    let dir = dir.canonicalize()?;
+    let moved: Vec<_> = outputs
+        .iter()
+        .filter(|path| path.is_file())
+        .filter(|path| path.parent().and_then(|p| p.canonicalize().ok()).as_ref() == Some(&dir))
+        .filter(|path| {
+            let name = path.file_name().map_or("", |n| n.to_str().unwrap_or(""));
+            !has_ext(name, keep)
+        })
+        .cloned()
+        .collect();
+    for path in moved {
+        let name = path.file_name().map_or("", |n| n.to_str().unwrap_or(""));
+        let sub = dir.join(if has_ext(name, logs) {
+            LOGS_DIR
+        } else {
+            AUX_DIR
+        });
+        std::fs::create_dir_all(&sub)?;
+        let to = sub.join(name);
+        std::fs::rename(&path, &to)?;
+        outputs.remove(&path);
+        outputs.insert(to);
+    }
+    Ok(())
+}
+
+/// Remove the subdirectories of `dir`, if cleaning left them empty
+pub fn remove_empty(dir: &Path) {
+    for sub in [AUX_DIR, LOGS_DIR] {
+        let _ = std::fs::remove_dir(dir.join(sub));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::TempDir;
+
+    #[test]
+    fn sort_and_restore() {
+        let dir = TempDir::new("group-test").unwrap();
+        let d = dir.path.canonicalize().unwrap();
+        let mut outputs = HashSet::new();
+        for name in [
+            "paper.pdf",
+            "paper.log",
+            "paper.blg",
+            "paper.aux",
+            "paper.toc",
+        ] {
+            std::fs::write(d.join(name), name).unwrap();
+            outputs.insert(d.join(name));
+        }
+        // Only outputs directly in the directory are moved
+        std::fs::create_dir(d.join("figures")).unwrap();
+        std::fs::write(d.join("figures/plot.aux"), "").unwrap();
+        outputs.insert(d.join("figures/plot.aux"));
+        std::fs::write(d.join("paper.tex"), "").unwrap();
+
+        sort(&d, &mut outputs, &["pdf"], &["log", "blg"]).unwrap();
+        let mut expected: HashSet<_> = [
+            "paper.pdf",
+            "logs/paper.log",
+            "logs/paper.blg",
+            "aux/paper.aux",
+            "aux/paper.toc",
+            "figures/plot.aux",
+        ]
+        .iter()
+        .map(|name| d.join(name))
+        .collect();
+        assert_eq!(outputs, expected);
+        for path in expected.iter() {
+            assert!(path.is_file(), "{}", path.display());
+        }
+        assert!(d.join("paper.tex").is_file());
+        assert_eq!(
+            std::fs::read_to_string(d.join("aux/paper.aux")).unwrap(),
+            "paper.aux"
+        );
+
+        restore(&d).unwrap();
+        expected = [
+            "paper.pdf",
+            "paper.log",
+            "paper.blg",
+            "paper.aux",
+            "paper.toc",
+        ]
+        .iter()
+        .map(|name| d.join(name))
+        .collect();
+        for path in expected.iter() {
+            assert!(path.is_file(), "{}", path.display());
+        }
+        assert!(d.join("figures/plot.aux").is_file());
+
+        // Left empty by the restore, like clean leaves them
+        remove_empty(&d);
+        assert!(!d.join("aux").exists());
+        assert!(!d.join("logs").exists());
+    }
+
+    #[test]
+    fn non_empty_dirs_are_kept() {
+        let dir = TempDir::new("group-test").unwrap();
+        std::fs::create_dir(dir.path.join(AUX_DIR)).unwrap();
+        std::fs::write(dir.path.join("aux/notes.txt"), "").unwrap();
+        std::fs::create_dir(dir.path.join(LOGS_DIR)).unwrap();
+        remove_empty(&dir.path);
+        assert!(dir.path.join("aux/notes.txt").is_file());
+        assert!(!dir.path.join(LOGS_DIR).exists());
+    }
+}
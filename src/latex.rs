@@ -4,35 +4,298 @@
 // Distributed under terms of the MIT license.
 //
 
-use std::collections::HashMap;
-
-use crate::{recipe::Recipe, Options};
-
-pub fn make_cmds(options: &Options, map: &mut HashMap<String, Recipe>) {
-    // pdflatex
-    map.insert(
-        "pdf".into(),
-        Recipe {
-            uses: "tex",
-            extras: &[],
-            generated: &["fls", "synctex.gz"],
-            generated_dirs: &[],
-            script:
-                "pdflatex -recorder -file-line-error -interaction nonstopmode -synctex 1 \"%I\""
-                    .into(),
-        },
-    );
-    // dvilualatex
-    map.insert(
-        "dvi".into(),
-        Recipe {
-            uses: "tex",
-            extras: &[],
-            generated: &[],
-            generated_dirs: &[],
-            script: "dvilualatex --recorder --file-line-error --interaction=nonstopmode --synctex=1 \"%I\"".into(),
-        },
-    );
+use std::{collections::HashMap, process::Command, str::FromStr};
+
+use crate::{
+    recipe::{Recipe, RecipeProvider},
+    util::{self, shell_quote},
+    Options, OutFmt,
+};
+
+/// Latex recipes
+pub struct Latex;
+
+/// Engine used to build pdfs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Engine {
+    Pdflatex,
+    Xelatex,
+    Lualatex,
+}
+
+impl Engine {
+    /// Name of the executable
+    pub fn command(&self) -> &'static str {
+        match self {
+            Self::Pdflatex => "pdflatex",
+            Self::Xelatex => "xelatex",
+            Self::Lualatex => "lualatex",
+        }
+    }
+
+    /// The executable, as the tools its recipe requires
+    fn requires(&self) -> &'static [&'static str] {
+        match self {
+            Self::Pdflatex => &["pdflatex"],
+            Self::Xelatex => &["xelatex"],
+            Self::Lualatex => &["lualatex"],
+        }
+    }
+}
+
+impl FromStr for Engine {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "pdflatex" => Ok(Self::Pdflatex),
+            "xelatex" => Ok(Self::Xelatex),
+            "lualatex" => Ok(Self::Lualatex),
+            _ => Err(format!(
+                "unknown engine `{}`, expected pdflatex, xelatex or lualatex",
+                s
+            )),
+        }
+    }
+}
+
+/// How `--dvi-to-pdf` converts the dvi
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DviToPdf {
+    Dvipdfmx,
+    /// dvips, then ps2pdf
+    Dvips,
+}
+
+impl FromStr for DviToPdf {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "dvipdfmx" => Ok(Self::Dvipdfmx),
+            "dvips" => Ok(Self::Dvips),
+            _ => Err(format!(
+                "unknown dvi converter `{}`, expected dvipdfmx or dvips",
+                s
+            )),
+        }
+    }
+}
+
+/// Recipe converting a dvi to a pdf, for `--dvi-to-pdf`
+pub fn dvi_to_pdf(options: &Options) -> Recipe {
+    let (generated, script, requires): (&'static [&'static str], _, &'static [&'static str]) =
+        match options.dvi_to_pdf_via {
+            DviToPdf::Dvipdfmx => (&[], "dvipdfmx -o %O %I", &["dvipdfmx"]),
+            DviToPdf::Dvips => (
+                &[".ps"],
+                "dvips -o %N.ps %I && ps2pdf %N.ps %O",
+                &["dvips", "ps2pdf"],
+            ),
+        };
+    Recipe {
+        uses: "dvi",
+        generated,
+        script: script.into(),
+        requires,
+        ..Recipe::default()
+    }
+}
+
+/// Name of the program used to build the final output
+pub fn engine_name(options: &Options) -> &'static str {
+    match options.output_format() {
+        OutFmt::Pdf => options.engine.unwrap_or(Engine::Pdflatex).command(),
+        OutFmt::Dvi => "dvilualatex",
+        OutFmt::Html => "make4ht",
+    }
+}
+
+/// Whether the output of a failed pdflatex run shows the document needs xelatex or lualatex
+pub fn needs_unicode_engine(log: &str) -> bool {
+    const ERRORS: &[&str] = &[
+        "The fontspec package requires either XeTeX or",
+        "not set up for use with LaTeX",
+    ];
+    const COMMANDS: &[&str] = &["\\setmainfont", "\\setsansfont", "\\setmonofont"];
+    if ERRORS.iter().any(|e| log.contains(e)) {
+        return true;
+    }
+    // The line the error was on follows it, as `l.12 \setmainfont`
+    log.split("Undefined control sequence").skip(1).any(|rest| {
+        rest.lines()
+            .find(|line| line.starts_with("l."))
+            .is_some_and(|line| COMMANDS.iter().any(|c| line.contains(c)))
+    })
+}
+
+/// Timestamp to embed in reproducible builds
+///
+/// Uses the time of the last git commit, or the unix epoch outside of a git repository
+fn source_date_epoch() -> String {
+    if let Ok(epoch) = std::env::var("SOURCE_DATE_EPOCH") {
+        return epoch;
+    }
+    util::output(Command::new("git").args(["log", "-1", "--format=%ct"]))
+        .ok()
+        .filter(|o| o.status.success())
+        .map(|o| String::from_utf8_lossy(&o.stdout).trim().to_string())
+        .filter(|s| !s.is_empty())
+        .unwrap_or_else(|| "0".into())
+}
+
+/// Escape the characters TeX treats specially in `text`
+fn tex_escape(text: &str) -> String {
+    let mut ret = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\\' => ret.push_str("\\textbackslash{}"),
+            '~' => ret.push_str("\\textasciitilde{}"),
+            '^' => ret.push_str("\\textasciicircum{}"),
+            '{' | '}' | '$' | '&' | '#' | '_' | '%' => {
+                ret.push('\\');
+                ret.push(c);
+            }
+            c => ret.push(c),
+        }
+    }
+    ret
+}
+
+/// Code to run before the document, or `None` if there isn't any
+fn pretex(options: &Options) -> Option<String> {
+    let text = options.draft_banner.as_ref()?;
+    // Packages can't be loaded before `\documentclass`, so wait for the preamble to end
+    Some(format!(
+        "\\AddToHook{{begindocument/before}}{{\\RequirePackage{{draftwatermark}}\\SetWatermarkText{{{}}}}}",
+        tex_escape(text)
+    ))
+}
+
+/// The file argument for the engine, which runs the pretex before inputting the file
+fn input_arg(options: &Options) -> String {
+    match pretex(options) {
+        // The quoted parts are joined into one argument by the shell. The jobname would be taken
+        // from the first file input otherwise, which could be one the pretex loads
+        Some(pretex) => format!(
+            "-jobname %N {}%I'}}'",
+            shell_quote(&format!("{}\\input{{", pretex)).replace('%', "%%")
+        ),
+        None => "%I".into(),
+    }
+}
+
+/// Notice for luaotfload building its font names database, which can take minutes on the
+/// first run of lualatex
+fn font_cache_notice(line: &str) -> Option<&'static str> {
+    let (_, message) = line.split_once("luaotfload | db :")?;
+    [
+        "Reading font names",
+        "generating new one",
+        "please be patient",
+    ]
+    .iter()
+    .any(|m| message.contains(m))
+    .then_some("Building the font cache, this may take a while (first run only)")
+}
+
+/// The script running `program`, one of the LaTeX engines, on the document
+///
+/// Options are given with a single dash and separate values, which both TeX Live and MiKTeX
+/// accept for every engine
+fn engine_script(program: &str, options: &Options) -> String {
+    let mut args = String::new();
+    if options.shell_escape {
+        args.push_str(" -shell-escape");
+    }
+    for arg in options.latex_args.iter() {
+        args.push(' ');
+        args.push_str(&shell_quote(arg).replace('%', "%%"));
+    }
+    // Paths in the synctex data are relative to where the engine runs, which stays the source
+    // directory, so forward & inverse search still work from the output directory
+    if let Some(dir) = &options.output_dir {
+        args.push_str(" -output-directory ");
+        args.push_str(&shell_quote(&dir.display().to_string()));
+    }
+    format!(
+        "{} -recorder -file-line-error -interaction nonstopmode -synctex {}{} {}",
+        program,
+        if options.no_synctex { 0 } else { 1 },
+        args,
+        input_arg(options)
+    )
+}
+
+/// Cache directory written by `minted`, named `_minted-<jobname>` (or just `_minted` since v3)
+const MINTED: &[&str] = &["_minted"];
+
+impl RecipeProvider for Latex {
+    fn register(&self, options: &Options, map: &mut HashMap<String, Recipe>) {
+        let env = if options.reproducible {
+            vec![
+                ("SOURCE_DATE_EPOCH", source_date_epoch()),
+                ("FORCE_SOURCE_DATE", "1".into()),
+            ]
+        } else {
+            vec![]
+        };
+        let generated: &'static [&'static str] = if options.no_synctex {
+            &["fls"]
+        } else {
+            &["fls", "synctex.gz"]
+        };
+        let engine = options.engine.unwrap_or(Engine::Pdflatex);
+        let progress = |lua: bool| {
+            if options.verbose && lua {
+                Some(font_cache_notice as fn(&str) -> Option<&'static str>)
+            } else {
+                None
+            }
+        };
+        // pdflatex, or the selected engine
+        map.insert(
+            "pdf".into(),
+            Recipe {
+                uses: "tex",
+                generated,
+                generated_dirs: MINTED,
+                script: engine_script(engine.command(), options).into(),
+                env: env.clone(),
+                progress: progress(engine == Engine::Lualatex),
+                requires: engine.requires(),
+                ..Recipe::default()
+            },
+        );
+        // dvilualatex
+        map.insert(
+            "dvi".into(),
+            Recipe {
+                uses: "tex",
+                generated_dirs: MINTED,
+                script: engine_script("dvilualatex", options).into(),
+                env: env.clone(),
+                progress: progress(true),
+                requires: &["dvilualatex"],
+                ..Recipe::default()
+            },
+        );
+        // make4ht
+        map.insert(
+            "html".into(),
+            Recipe {
+                uses: "tex",
+                generated: &[
+                    "css", "4ct", "4tc", "dvi", "idv", "lg", "tmp", "xref", "fls", "aux", "log",
+                ],
+                generated_dirs: MINTED,
+                script: "make4ht -ul %I".into(),
+                env,
+                requires: &["make4ht"],
+                ..Recipe::default()
+            },
+        );
+    }
 }
 
 // TODO: Known latex warnings
@@ -41,3 +304,91 @@ pub fn make_cmds(options: &Options, map: &mut HashMap<String, Recipe>) {
 //LaTeX Warning: Label(s) may have changed. Rerun to get cross-references right.
 //
 //
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Clap;
+
+    fn recipes(args: &[&str]) -> HashMap<String, Recipe> {
+        let options = Options::try_parse_from(["latexmk"].iter().chain(args)).unwrap();
+        let mut map = HashMap::new();
+        Latex.register(&options, &mut map);
+        map
+    }
+
+    #[test]
+    fn engine_flags() {
+        for (engine, program) in [
+            (None, "pdflatex"),
+            (Some("pdflatex"), "pdflatex"),
+            (Some("xelatex"), "xelatex"),
+            (Some("lualatex"), "lualatex"),
+        ] {
+            let args: Vec<_> = engine.map(|e| vec!["--engine", e]).unwrap_or_default();
+            let map = recipes(&args);
+            let expected = format!(
+                "{} -recorder -file-line-error -interaction nonstopmode -synctex 1 %I",
+                program
+            );
+            assert_eq!(map["pdf"].script, expected);
+        }
+    }
+
+    #[test]
+    fn dvi_flags_match_pdf() {
+        let map = recipes(&["--shell-escape", "-o", "build dir"]);
+        let flags =
+            " -recorder -file-line-error -interaction nonstopmode -synctex 1 -shell-escape \
+             -output-directory 'build dir' %I";
+        assert_eq!(map["pdf"].script, format!("pdflatex{}", flags));
+        assert_eq!(map["dvi"].script, format!("dvilualatex{}", flags));
+        assert!(!map["dvi"].script.contains("--"));
+    }
+
+    #[test]
+    fn requires_engine() {
+        assert_eq!(recipes(&[])["pdf"].requires, &["pdflatex"]);
+        for engine in ["pdflatex", "xelatex", "lualatex"] {
+            assert_eq!(recipes(&["--engine", engine])["pdf"].requires, &[engine]);
+        }
+        assert_eq!(recipes(&[])["dvi"].requires, &["dvilualatex"]);
+        assert_eq!(recipes(&[])["html"].requires, &["make4ht"]);
+    }
+
+    #[test]
+    fn without_synctex() {
+        let map = recipes(&[]);
+        assert!(map["pdf"].script.contains(" -synctex 1 "));
+        assert!(map["pdf"].generated.contains(&"synctex.gz"));
+        let map = recipes(&["--no-synctex"]);
+        for recipe in map.values() {
+            assert!(!recipe.script.contains(" -synctex 1 "));
+            assert!(!recipe.generated.contains(&"synctex.gz"));
+        }
+        assert!(map["pdf"].script.contains(" -synctex 0 "));
+        assert!(map["dvi"].script.contains(" -synctex 0 "));
+    }
+
+    #[test]
+    fn reproducible_env() {
+        for recipe in recipes(&[]).values() {
+            assert!(recipe.env.is_empty());
+        }
+        // The one given by the caller wins over the time of the last commit
+        std::env::set_var("SOURCE_DATE_EPOCH", "1234567890");
+        let map = recipes(&["--reproducible"]);
+        std::env::remove_var("SOURCE_DATE_EPOCH");
+        for ext in ["pdf", "dvi", "html"] {
+            assert_eq!(
+                map[ext].env,
+                [
+                    ("SOURCE_DATE_EPOCH", "1234567890".to_string()),
+                    ("FORCE_SOURCE_DATE", "1".to_string())
+                ],
+                "{}",
+                ext
+            );
+        }
+    }
+}
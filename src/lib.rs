@@ -0,0 +1,574 @@
+//! Latexmk like build tool
+//!
+//! latexmk supports way more options, but the defaults are good enough for most people.
+//!
+//! The build engine can also be used as a library. Extra recipes can be plugged in by
+//! implementing [`RecipeProvider`] and passing it to [`build_with`]. The command line tool itself
+//! is [`main`].
+//!
+//! TODO:
+//! - Support custom recipes (A few more options need to be added...)
+//! - More builtin options
+//! + Clean operation
+//! - Log files allowing clean to avoid running all files, and potentially faster opteration?
+
+use std::{
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use clap::Clap;
+use clap_generate::Shell;
+
+mod buildlog;
+mod cli;
+mod completions;
+mod config;
+mod count;
+mod dtx;
+mod flatten;
+mod fonts;
+mod glossaries;
+mod gnuplot;
+mod group;
+mod latex;
+mod lock;
+mod packages;
+mod pdfa;
+mod profile;
+mod recipe;
+mod rmarkdown;
+mod sage;
+mod scan;
+mod selftest;
+mod serve;
+mod util;
+mod view;
+mod watch;
+
+use util::message;
+
+pub use cli::main;
+pub use glossaries::Glossaries;
+pub use latex::{DviToPdf, Engine, Latex};
+pub use recipe::{
+    build, build_with, builtin_providers, Bibtex, BuildReport, Deps, Recipe, RecipeProvider,
+    ToolNotFound,
+};
+pub use sage::Sage;
+pub use util::{ColorChoice, OutputName};
+pub use view::SourcePos;
+
+/// Command line tool to automatically build latex documents
+#[derive(Debug, Clone, Clap)]
+pub struct Options {
+    /// Compile to dvi rather than pdf
+    ///
+    /// Same as `--outfmt dvi`
+    #[clap(short, long)]
+    pub dvi: bool,
+    /// Convert the dvi to a pdf after building, implies --dvi
+    #[clap(long, conflicts_with = "outfmt")]
+    pub dvi_to_pdf: bool,
+    /// How --dvi-to-pdf converts the dvi: dvipdfmx, or dvips then ps2pdf
+    #[clap(long, default_value = "dvipdfmx")]
+    pub dvi_to_pdf_via: latex::DviToPdf,
+    /// Format of the final output: pdf, dvi or html [default: pdf]
+    ///
+    /// html is built with make4ht
+    #[clap(long)]
+    pub outfmt: Option<OutFmt>,
+    /// Engine used to build pdfs [default: pdflatex]
+    ///
+    /// Documents can also select one with a `% !TEX program = <engine>` comment at the start of
+    /// the file, or in a `<stem>.latexmk.toml` next to it. A `latexmk.toml` in the current
+    /// directory, or `~/.config/latexmk/config.toml`, selects one for every document
+    #[clap(long)]
+    pub engine: Option<latex::Engine>,
+    /// Rebuild a document with xelatex if it fails under pdflatex with errors showing it needs
+    /// a unicode engine, e.g. from fontspec
+    #[clap(long)]
+    pub auto_engine: bool,
+    /// Directory the documents and other paths are relative to, instead of the current one
+    ///
+    /// Everything runs as if latexmk was started there
+    #[clap(long)]
+    pub relative_to: Option<PathBuf>,
+    /// Directory to write the output and intermediate files to, instead of next to the source
+    #[clap(short, long)]
+    pub output_dir: Option<PathBuf>,
+    /// What a relative --output-dir is relative to: cwd or source
+    ///
+    /// With cwd, every document shares one output directory. With source, each document gets
+    /// its own, next to its source.
+    #[clap(long, default_value = "cwd")]
+    pub output_dir_mode: OutputDirMode,
+    /// Sort the files in the output directory, moving the logs to `logs/` and the other
+    /// intermediate files to `aux/`
+    ///
+    /// The final output and its synctex data stay at the top
+    #[clap(long, requires = "output-dir")]
+    pub group_output: bool,
+    /// Automatically clean up generated files
+    ///
+    /// Note that this still runs the full build process, since latexmk doesn't keep a log of the
+    /// generated files between runs
+    #[clap(short, long)]
+    pub clean: bool,
+    /// Remove the output directory, instead of building
+    ///
+    /// This is refused if it contains any sources, so only a dedicated build directory is
+    /// removed
+    #[clap(long, requires = "output-dir")]
+    pub clean_output_dir: bool,
+    /// Extra file extensions clean should never remove
+    ///
+    /// The final output (pdf or dvi) is always protected, as are outputs that are slow to
+    /// regenerate, like sagetex's `.sagetex.sout`
+    #[clap(long, multiple_occurrences = true, multiple_values = false)]
+    pub protect: Vec<String>,
+    /// Extra extensions clean removes next to each document, e.g. `run.xml`
+    ///
+    /// `%R` stands for the name of the document, as in `%R-blx.bib`. Can be given multiple times
+    #[clap(long, multiple_occurrences = true, multiple_values = false)]
+    pub clean_ext: Vec<String>,
+    /// Keep the logs of latex, bibtex, makeindex & makeglossaries when cleaning
+    ///
+    /// Useful in CI, to archive the logs without the rest of the generated files
+    #[clap(long)]
+    pub keep_logs: bool,
+    /// Clean up the generated files of a document if it fails to build
+    ///
+    /// By default they're kept, to help with debugging. The final output is never removed
+    #[clap(long)]
+    pub clean_on_failure: bool,
+    /// Clean up the files from the previous build before building
+    ///
+    /// This guarantees the build starts from scratch, and implies --force
+    #[clap(long)]
+    pub fresh: bool,
+    /// Maximum number of latex passes per file
+    #[clap(long, default_value = "5")]
+    pub max_passes: usize,
+    /// Most times a recipe may run per file, e.g. `--max-runs bbl=2`
+    ///
+    /// Recipes are named by the extension they make, so `pdf` limits the latex passes. Can be
+    /// given multiple times
+    #[clap(long, multiple_occurrences = true, multiple_values = false)]
+    pub max_runs: Vec<RunLimit>,
+    /// Only run the recipes making these extensions, besides latex itself, e.g.
+    /// `--enable-recipe bbl`
+    ///
+    /// Can be given multiple times
+    #[clap(long, multiple_occurrences = true, multiple_values = false)]
+    pub enable_recipe: Vec<String>,
+    /// Never run the recipe making this extension, e.g. `--disable-recipe bbl` to skip the
+    /// bibliography
+    ///
+    /// Latex itself can't be disabled. Can be given multiple times
+    #[clap(long, multiple_occurrences = true, multiple_values = false)]
+    pub disable_recipe: Vec<String>,
+    /// Stop building once the generated files add up to more than this many MB
+    ///
+    /// Checked after every latex pass, as a safety net for untrusted documents
+    #[clap(long)]
+    pub max_output_size: Option<u64>,
+    /// Fail when the output is known to be wrong, e.g. because its fonts are missing
+    /// characters the document uses
+    #[clap(long)]
+    pub strict: bool,
+    /// Run latex exactly once, without building dependencies or rerunning
+    #[clap(long)]
+    pub single_pass: bool,
+    /// Extra argument to pass to bibtex, e.g. `--bibtex-args -min-crossrefs=99`
+    ///
+    /// Can be given multiple times
+    #[clap(
+        long,
+        multiple_occurrences = true,
+        multiple_values = false,
+        allow_hyphen_values = true
+    )]
+    pub bibtex_args: Vec<String>,
+    /// Style file for makeglossaries to use, instead of the one the document sets
+    #[clap(long, value_name = "FILE")]
+    pub glossary_style: Option<PathBuf>,
+    /// Extra argument to pass to the latex engine, e.g. `--latex-args -halt-on-error`
+    ///
+    /// Can be given multiple times
+    #[clap(
+        long,
+        multiple_occurrences = true,
+        multiple_values = false,
+        allow_hyphen_values = true
+    )]
+    pub latex_args: Vec<String>,
+    /// Install packages the document needs but are missing, then rebuild
+    ///
+    /// This only works with MiKTeX. Otherwise the command to install them is printed.
+    #[clap(long)]
+    pub auto_install: bool,
+    /// Let the document run external programs, as needed by e.g. minted
+    ///
+    /// Only use this for documents you trust
+    #[clap(long)]
+    pub shell_escape: bool,
+    /// Shell command to run before building each document, failing the build if it fails
+    ///
+    /// Runs in the current directory. `%I` is replaced by the document, `%O` by the final
+    /// output, `%N` by the document's name without an extension and `%%` by a literal percent.
+    #[clap(long, value_name = "CMD")]
+    pub pre_build: Option<String>,
+    /// Shell command to run after each document is built, with the same replacements as
+    /// --pre-build
+    ///
+    /// A failure is reported, but only fails the build with --post-build-fatal
+    #[clap(long, value_name = "CMD")]
+    pub post_build: Option<String>,
+    /// Fail the build if the --post-build command fails
+    #[clap(long)]
+    pub post_build_fatal: bool,
+    /// Also build the figures using the `standalone` class that each document includes, to their
+    /// own pdfs
+    #[clap(long)]
+    pub build_standalone: bool,
+    /// Extra argument to pass to gnuplot when making gnuplottex figures
+    ///
+    /// Can be given multiple times
+    #[clap(
+        long,
+        multiple_occurrences = true,
+        multiple_values = false,
+        allow_hyphen_values = true
+    )]
+    pub gnuplot_args: Vec<String>,
+    /// Add a diagonal watermark with this text to every page, e.g. `--draft-banner DRAFT`
+    ///
+    /// This needs the draftwatermark package, and isn't supported for html
+    #[clap(long)]
+    pub draft_banner: Option<String>,
+    /// Always run latex, even if the output is newer than all of the inputs
+    #[clap(short, long)]
+    pub force: bool,
+    /// Write the intermediate files to `.latexmk-build/`, and only replace the final output once
+    /// it's complete
+    ///
+    /// This lets the build succeed while a viewer has the output open
+    #[clap(long)]
+    pub atomic_output: bool,
+    /// Build in a copy of the source directory, and only copy back the final output
+    ///
+    /// This guarantees no stale intermediate files affect the build
+    #[clap(long)]
+    pub tempdir: bool,
+    /// Don't remove the temporary directory used by --tempdir, for debugging
+    #[clap(long, requires = "tempdir")]
+    pub keep_tempdir: bool,
+    /// Build `diff.pdf`, showing the changes from OLD to NEW using latexdiff
+    #[clap(
+        long,
+        number_of_values = 2,
+        value_names = &["OLD", "NEW"],
+        conflicts_with = "files"
+    )]
+    pub diff: Vec<PathBuf>,
+    /// Only build the documents with sources changed since REF, according to `git diff`
+    ///
+    /// Sources are found from the files recorded by the last build, so documents without a
+    /// previous build are always built
+    #[clap(long, value_name = "REF")]
+    pub since: Option<String>,
+    /// Include files ignored by git when looking for files to compile
+    #[clap(long)]
+    pub no_respect_gitignore: bool,
+    /// Files to compile, or `-` to read a single document from stdin [default: ./*.tex, except files ignored by git]
+    ///
+    /// `.Rmd` files are rendered with R's rmarkdown package instead. Documents a file reads labels
+    /// from with `\externaldocument{other}` are built before it, when there's an `other.tex`, even
+    /// if they aren't given here
+    pub files: Vec<PathBuf>,
+    /// Output shell completion script
+    ///
+    /// Supported shells: [bash, elvish, fish, powershell, zsh]
+    /// Note that this overrides any other settings specified
+    #[clap(long)]
+    pub shell_completion: Option<Shell>,
+    /// Install the shell completion script where the shell picks it up
+    ///
+    /// Supported shells: [bash, fish, zsh], defaulting to the one from `SHELL`
+    /// Note that this overrides any other settings specified
+    #[clap(long, value_name = "SHELL")]
+    pub install_completions: Option<Option<Shell>>,
+    /// Write the pdf to stdout once it's built, printing everything else to stderr
+    #[clap(long, conflicts_with_all = &["watch", "view", "goto-line"])]
+    pub stdout_pdf: bool,
+    /// Make the output reproducible by fixing the timestamps embedded by the engine
+    ///
+    /// Uses `SOURCE_DATE_EPOCH` if set, otherwise the time of the last git commit
+    #[clap(long)]
+    pub reproducible: bool,
+    /// Copy the final output to a name built from a template, e.g. `{stem}-{date}.pdf`
+    ///
+    /// Supported placeholders: {stem}, {date}, {engine}, {git}
+    #[clap(long)]
+    pub output_name: Option<util::OutputName>,
+    /// After building, write the bibliography entries the document cites to FILE with bibexport
+    ///
+    /// This is a deliverable, so cleaning never removes it
+    #[clap(long, value_name = "FILE")]
+    pub export_bib: Option<PathBuf>,
+    /// After building, check that the pdf conforms to PDF/A with veraPDF
+    #[clap(long)]
+    pub validate_pdfa: bool,
+    /// Rebuild whenever one of the inputs changes
+    #[clap(short, long)]
+    pub watch: bool,
+    /// Milliseconds to wait for changes to stop before rebuilding in watch mode
+    #[clap(long, default_value = "300")]
+    pub watch_debounce: u64,
+    /// Maximum number of files to watch in watch mode
+    #[clap(long, default_value = "1000")]
+    pub max_file_watches: usize,
+    /// In watch mode, report the state of the builds as JSON over HTTP on PORT of localhost
+    ///
+    /// The endpoints are `/status`, `/diagnostics` and `/deps`
+    #[clap(long, value_name = "PORT", requires = "watch")]
+    pub serve: Option<u16>,
+    /// Check the installation by building a small bundled document
+    ///
+    /// Note that this overrides any other settings specified
+    #[clap(long)]
+    pub selftest: bool,
+    /// Update the modification time of FILE to now, like `touch`, instead of building
+    #[clap(
+        long,
+        value_name = "FILE",
+        multiple_occurrences = true,
+        multiple_values = false
+    )]
+    pub touch: Vec<PathBuf>,
+    /// When to color messages: auto, always or never
+    ///
+    /// Setting the NO_COLOR environment variable disables color regardless
+    #[clap(long, default_value = "auto")]
+    pub color: util::ColorChoice,
+    /// Show paths in messages, logs and --serve as they are, instead of relative to the
+    /// current directory
+    #[clap(long)]
+    pub no_relative_paths: bool,
+    /// Print more about what's being done, like the settings read for each document
+    #[clap(short, long)]
+    pub verbose: bool,
+    /// Show everything the engine printed when a build fails, including the files it opened
+    #[clap(long)]
+    pub raw_log: bool,
+    /// Print every command before running it, in a form that can be run by hand
+    #[clap(long)]
+    pub trace: bool,
+    /// Write a timeline of the recipes that were run to this file
+    ///
+    /// This is in the Chrome trace format, which Perfetto or `chrome://tracing` can open
+    #[clap(long, value_name = "FILE")]
+    pub profile: Option<PathBuf>,
+    /// Print the programs a build would likely run for each file, without running anything
+    ///
+    /// This is a guess from the sources and the last build, since what's needed is only known
+    /// once the engine has run
+    #[clap(long)]
+    pub plan: bool,
+    /// Same as --plan, but printed as a JSON array with an object for each file
+    ///
+    /// Each has the `file`, whether it's `up_to_date`, the `steps` a build would run and the
+    /// programs among them that are `missing`
+    #[clap(long)]
+    pub plan_json: bool,
+    /// Print the files recorded in the `.fls` of each file after building, for debugging
+    #[clap(long)]
+    pub dump_fls: bool,
+    /// Write the output of every command that was run to this file, with a header for each
+    #[clap(long)]
+    pub log_file: Option<PathBuf>,
+    /// Print the final `.log` of each file after building
+    #[clap(long)]
+    pub print_log: bool,
+    /// Print the word count of each file after building, using texcount
+    #[clap(long)]
+    pub count: bool,
+    /// Only print the word counts, without building
+    #[clap(long)]
+    pub count_only: bool,
+    /// Write the document with the files it `\input`s & `\include`s inlined to FILE, instead
+    /// of building
+    #[clap(long, value_name = "FILE")]
+    pub flatten: Option<PathBuf>,
+    /// Also copy the images the document uses next to the --flatten output
+    #[clap(long, requires = "flatten")]
+    pub flatten_graphics: bool,
+    /// Print the class & packages each file loaded, with their versions, after building
+    #[clap(long)]
+    pub list_packages: bool,
+    /// Don't lock documents while they're built
+    ///
+    /// Otherwise each document is locked with a `<stem>.latexmk.lock` next to the output, so
+    /// another run can't build it at the same time
+    #[clap(long)]
+    pub no_lock: bool,
+    /// Don't write synctex data, which is used for jumping between the source and the pdf
+    ///
+    /// This makes builds a little faster, but --goto-line can only open page 1
+    #[clap(long)]
+    pub no_synctex: bool,
+    /// Open the output in a viewer after building
+    #[clap(long)]
+    pub view: bool,
+    /// Open the viewer at the page generated from a source line, given as `LINE[:COLUMN]:FILE`
+    ///
+    /// Uses `synctex view` to find the page, and implies --view
+    #[clap(long)]
+    pub goto_line: Option<view::SourcePos>,
+}
+
+impl Options {
+    /// Format `path` for messages, according to `--no-relative-paths`
+    pub fn display_path(&self, path: &Path) -> String {
+        if self.no_relative_paths {
+            path.display().to_string()
+        } else {
+            util::display_path(path)
+        }
+    }
+
+    /// The output directory for the document at `file`, according to `--output-dir-mode`
+    pub fn output_dir_for(&self, file: &Path) -> Option<PathBuf> {
+        let dir = self.output_dir.as_ref()?;
+        match self.output_dir_mode {
+            OutputDirMode::Cwd => Some(dir.clone()),
+            // Absolute directories are kept as they are
+            OutputDirMode::Source => Some(file.parent().unwrap_or_else(|| Path::new("")).join(dir)),
+        }
+    }
+
+    /// Format of the final output
+    pub fn output_format(&self) -> OutFmt {
+        if self.dvi || self.dvi_to_pdf {
+            OutFmt::Dvi
+        } else {
+            self.outfmt.unwrap_or(OutFmt::Pdf)
+        }
+    }
+}
+
+/// Exit status of the command line tool
+///
+/// These follow real latexmk where it has an equivalent, so scripts written for it keep working
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitCode {
+    /// Everything was built, or was already up to date
+    Success = 0,
+    /// The command line arguments were invalid
+    Usage = 10,
+    /// A document failed to build
+    Failed = 12,
+    /// A program needed to build a document isn't installed
+    ToolNotFound = 127,
+}
+
+impl ExitCode {
+    /// Exit code for the outcome of a build
+    pub fn of(report: &BuildReport) -> Self {
+        if report.success {
+            Self::Success
+        } else if report.tool_not_found {
+            Self::ToolNotFound
+        } else {
+            Self::Failed
+        }
+    }
+
+    /// Exit the process with this code
+    pub fn exit(self) -> ! {
+        std::process::exit(self as i32)
+    }
+}
+
+/// Final output format
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutFmt {
+    Pdf,
+    Dvi,
+    Html,
+}
+
+impl OutFmt {
+    /// Extension of the output file
+    pub fn ext(&self) -> &'static str {
+        match self {
+            Self::Pdf => "pdf",
+            Self::Dvi => "dvi",
+            Self::Html => "html",
+        }
+    }
+}
+
+impl FromStr for OutFmt {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "pdf" => Ok(Self::Pdf),
+            "dvi" => Ok(Self::Dvi),
+            "html" => Ok(Self::Html),
+            _ => Err(format!("unknown format `{}`, expected pdf, dvi or html", s)),
+        }
+    }
+}
+
+/// What a relative output directory is relative to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputDirMode {
+    /// The current directory
+    Cwd,
+    /// The directory containing each document
+    Source,
+}
+
+impl FromStr for OutputDirMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "cwd" => Ok(Self::Cwd),
+            "source" => Ok(Self::Source),
+            _ => Err(format!(
+                "unknown output directory mode `{}`, expected cwd or source",
+                s
+            )),
+        }
+    }
+}
+
+/// Limit on the runs of a recipe, for `--max-runs`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RunLimit {
+    /// The extension the recipe makes
+    pub recipe: String,
+    pub runs: usize,
+}
+
+impl FromStr for RunLimit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (recipe, runs) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected `recipe=runs`, e.g. `bbl=2`, not `{}`", s))?;
+        let runs = runs
+            .trim()
+            .parse()
+            .map_err(|e| format!("invalid number of runs `{}`: {}", runs, e))?;
+        Ok(Self {
+            recipe: recipe.trim().trim_start_matches('.').to_string(),
+            runs,
+        })
+    }
+}
@@ -0,0 +1,117 @@
+//
+// lock.rs
+// Copyright (C) 2021 matthew <matthew@matthew-ubuntu>
+// Distributed under terms of the MIT license.
+//
+
+//! Lock preventing two builds of the same document at once
+//!
+//! An editor building on save and a build started by hand would otherwise overwrite each
+//! other's intermediate files. The lock is a `<stem>.latexmk.lock` next to the output, holding
+//! the pid of the process building it.
+
+use std::{
+    fs::OpenOptions,
+    io::{Error, ErrorKind, Write},
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use crate::{util, Options};
+
+/// Held while a document is being built, and removed when dropped
+#[derive(Debug)]
+pub struct BuildLock {
+    path: PathBuf,
+}
+
+/// Whether the process `pid` is still running
+fn is_running(pid: u32) -> bool {
+    let mut cmd = if cfg!(windows) {
+        let mut cmd = Command::new("tasklist");
+        cmd.args(["/NH", "/FI"]).arg(format!("PID eq {}", pid));
+        cmd
+    } else {
+        let mut cmd = Command::new("kill");
+        // The error is matched below, so it mustn't be translated
+        cmd.arg("-0").arg(pid.to_string()).env("LC_ALL", "C");
+        cmd
+    };
+    match util::output(&mut cmd) {
+        Ok(output) if cfg!(windows) => {
+            String::from_utf8_lossy(&output.stdout).contains(&pid.to_string())
+        }
+        // It also fails for a process owned by another user, which is still running
+        Ok(output) => {
+            output.status.success()
+                || !String::from_utf8_lossy(&output.stderr).contains("No such process")
+        }
+        // Can't tell, so don't take the lock away
+        Err(_) => true,
+    }
+}
+
+impl BuildLock {
+    /// Lock the build of `output`
+    ///
+    /// Fails if another process already holds the lock. A lock left behind by a process that
+    /// has exited is taken over.
+    pub fn acquire(options: &Options, output: &Path) -> std::io::Result<Self> {
+        let stem = output.file_stem().map_or("", |s| s.to_str().unwrap_or(""));
+        let path = output.with_file_name(format!("{}.latexmk.lock", stem));
+        // Bounded, in case the lock keeps being taken & released by someone else
+        for _ in 0..3 {
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(mut file) => {
+                    write!(file, "{}", std::process::id())?;
+                    return Ok(Self { path });
+                }
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => (),
+                Err(e) => return Err(e),
+            }
+            let holder = std::fs::read_to_string(&path)
+                .ok()
+                .and_then(|pid| pid.trim().parse().ok());
+            match holder {
+                Some(pid) if is_running(pid) => {
+                    return Err(Error::other(format!(
+                        "a build of {} is already in progress (pid {}), or remove {} if not",
+                        options.display_path(output),
+                        pid,
+                        options.display_path(&path)
+                    )))
+                }
+                // The holder may have only just created it
+                None if path.exists() => std::thread::sleep(std::time::Duration::from_millis(50)),
+                _ => {
+                    crate::message!("Removing stale lock {}", options.display_path(&path));
+                    let _ = std::fs::remove_file(&path);
+                }
+            }
+        }
+        Err(Error::other(format!(
+            "couldn't lock {}, remove it if no other build is running",
+            options.display_path(&path)
+        )))
+    }
+}
+
+impl Drop for BuildLock {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn running_processes() {
+        assert!(is_running(std::process::id()));
+        let mut child = Command::new("true").spawn().unwrap();
+        let pid = child.id();
+        child.wait().unwrap();
+        assert!(!is_running(pid));
+    }
+}
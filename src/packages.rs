@@ -0,0 +1,127 @@
+//
+// packages.rs
+// Copyright (C) 2021 matthew <matthew@matthew-ubuntu>
+// Distributed under terms of the MIT license.
+//
+
+//! Packages the TeX distribution is missing
+
+use std::process::Command;
+
+use crate::{message, util};
+
+/// The names of the packages & classes `log` says couldn't be found
+///
+/// These are reported as ``! LaTeX Error: File `foo.sty' not found.``, or with the file and
+/// line instead of the `!` with `-file-line-error`
+pub fn missing_packages(log: &str) -> Vec<String> {
+    let mut ret = vec![];
+    for rest in log.split("LaTeX Error: File ").skip(1) {
+        let rest = rest.trim_start_matches(['`', '\'']);
+        let name = match rest.split_once(" not found") {
+            Some((name, _)) => name.trim_end_matches('\''),
+            None => continue,
+        };
+        // Missing images are reported the same way
+        let name = match name
+            .strip_suffix(".sty")
+            .or_else(|| name.strip_suffix(".cls"))
+        {
+            Some(name) => name,
+            None => continue,
+        };
+        if !name.is_empty() && !ret.iter().any(|n| n == name) {
+            ret.push(name.to_string());
+        }
+    }
+    ret
+}
+
+/// A class or package a document loaded
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Loaded {
+    pub name: String,
+    /// Whether this is the document class
+    pub class: bool,
+    /// Release date, e.g. `2021/10/04`
+    pub date: Option<String>,
+    /// e.g. `v1.4n`
+    pub version: Option<String>,
+}
+
+impl std::fmt::Display for Loaded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name)?;
+        for part in self.date.iter().chain(self.version.iter()) {
+            write!(f, " {}", part)?;
+        }
+        if self.class {
+            write!(f, " (class)")?;
+        }
+        Ok(())
+    }
+}
+
+/// The classes & packages `log` says were loaded, in the order they were loaded
+///
+/// Each one announces itself with a `Document Class: name date version description` or
+/// `Package: name date version description` line
+pub fn loaded_packages(log: &str) -> Vec<Loaded> {
+    let mut ret: Vec<Loaded> = vec![];
+    for line in log.lines() {
+        let (rest, class) = if let Some(rest) = line.strip_prefix("Document Class: ") {
+            (rest, true)
+        } else if let Some(rest) = line.strip_prefix("Package: ") {
+            (rest, false)
+        } else {
+            continue;
+        };
+        let mut words = rest.split_whitespace();
+        let name = match words.next() {
+            Some(name) => name,
+            None => continue,
+        };
+        let mut date = None;
+        let mut version = None;
+        for word in words.take(2) {
+            if word.starts_with(|c: char| c.is_ascii_digit()) && date.is_none() {
+                date = Some(word.to_string());
+            } else if word.starts_with('v') && word[1..].starts_with(|c: char| c.is_ascii_digit()) {
+                version = Some(word.to_string());
+            }
+        }
+        if !ret.iter().any(|p| p.name == name) {
+            ret.push(Loaded {
+                name: name.into(),
+                class,
+                date,
+                version,
+            });
+        }
+    }
+    ret
+}
+
+/// Whether the TeX distribution is MiKTeX, which can install packages by itself
+pub fn is_miktex() -> bool {
+    util::output(Command::new("pdflatex").arg("--version"))
+        .is_ok_and(|o| String::from_utf8_lossy(&o.stdout).contains("MiKTeX"))
+}
+
+/// Command to install `package`
+///
+/// Package names usually match the name of the `.sty`, but not always
+pub fn install_command(package: &str, miktex: bool) -> String {
+    if miktex {
+        format!("miktex packages install {}", package)
+    } else {
+        format!("tlmgr install {}", package)
+    }
+}
+
+/// Install `package` with MiKTeX, returning whether it succeeded
+pub fn install(package: &str) -> std::io::Result<bool> {
+    message!("Installing {}", package);
+    let output = util::output(Command::new("miktex").args(["packages", "install", package]))?;
+    Ok(output.status.success())
+}
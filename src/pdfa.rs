@@ -0,0 +1,54 @@
+//
+// pdfa.rs
+// Copyright (C) 2021 matthew <matthew@matthew-ubuntu>
+// Distributed under terms of the MIT license.
+//
+
+//! PDF/A validation, with veraPDF
+
+use std::{
+    io::{Error, ErrorKind},
+    path::Path,
+    process::Command,
+};
+
+use crate::{recipe::ToolNotFound, util};
+
+/// Check that the pdf at `pdf` conforms to the PDF/A flavour it claims
+///
+/// Returns the rules it breaks, which is empty if it conforms
+pub fn validate(pdf: &Path) -> std::io::Result<Vec<String>> {
+    let output = util::output(
+        Command::new("verapdf")
+            .args(["--format", "text", "-v"])
+            .arg(pdf),
+    )
+    .map_err(|e| match e.kind() {
+        ErrorKind::NotFound => Error::new(ErrorKind::NotFound, ToolNotFound("verapdf".into())),
+        _ => e,
+    })?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    // Each file gets a `PASS file flavour` or `FAIL file flavour` line, and with `-v` the rules
+    // that failed are listed under it
+    let mut verdict = None;
+    let mut failures = vec![];
+    for line in stdout.lines() {
+        if line.starts_with("PASS ") {
+            verdict = Some(true);
+        } else if line.starts_with("FAIL ") {
+            verdict = Some(false);
+        } else if let Some(rule) = line.trim_start().strip_prefix("FAIL ") {
+            failures.push(rule.trim().to_string());
+        }
+    }
+    match verdict {
+        Some(true) => Ok(vec![]),
+        Some(false) if failures.is_empty() => Ok(vec!["not PDF/A conformant".into()]),
+        Some(false) => Ok(failures),
+        None => Err(Error::other(format!(
+            "verapdf couldn't check {}: {}",
+            util::display_path(pdf),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))),
+    }
+}
@@ -0,0 +1,94 @@
+//
+// profile.rs
+// Copyright (C) 2021 matthew <matthew@matthew-ubuntu>
+// Distributed under terms of the MIT license.
+//
+
+//! Timeline of the recipes that were run, for `--profile`
+//!
+//! This is written in the Chrome trace event format, which `chrome://tracing` and Perfetto can
+//! display. Each recipe run is a begin & end event on the thread that ran it.
+
+use std::{collections::HashMap, path::Path, sync::Mutex, thread::ThreadId, time::Instant};
+
+use serde::Serialize;
+
+/// A single trace event
+#[derive(Debug, Serialize)]
+struct Event {
+    name: String,
+    cat: &'static str,
+    /// `B` to begin, `E` to end
+    ph: &'static str,
+    /// Microseconds since profiling started
+    ts: u128,
+    pid: u32,
+    tid: usize,
+}
+
+#[derive(Debug)]
+struct Profile {
+    start: Instant,
+    /// Threads numbered in the order they first ran a recipe
+    threads: HashMap<ThreadId, usize>,
+    events: Vec<Event>,
+}
+
+/// The profile being recorded, if `--profile` was given
+static PROFILE: Mutex<Option<Profile>> = Mutex::new(None);
+
+fn lock() -> std::sync::MutexGuard<'static, Option<Profile>> {
+    PROFILE.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Start recording recipe runs
+pub fn enable() {
+    *lock() = Some(Profile {
+        start: Instant::now(),
+        threads: HashMap::new(),
+        events: vec![],
+    });
+}
+
+fn event(name: &str, ph: &'static str) {
+    if let Some(profile) = lock().as_mut() {
+        let next = profile.threads.len();
+        let tid = *profile
+            .threads
+            .entry(std::thread::current().id())
+            .or_insert(next);
+        profile.events.push(Event {
+            name: name.into(),
+            cat: "recipe",
+            ph,
+            ts: profile.start.elapsed().as_micros(),
+            pid: std::process::id(),
+            tid,
+        });
+    }
+}
+
+/// Time `f` as an event called `name`, if profiling
+pub fn span<T>(name: &str, f: impl FnOnce() -> T) -> T {
+    event(name, "B");
+    let ret = f();
+    event(name, "E");
+    ret
+}
+
+/// Write the events recorded so far to `path`
+pub fn write(path: &Path) -> std::io::Result<()> {
+    #[derive(Serialize)]
+    #[serde(rename_all = "camelCase")]
+    struct Trace<'a> {
+        trace_events: &'a [Event],
+    }
+    let profile = lock();
+    let events = profile.as_ref().map_or(&[][..], |p| &p.events[..]);
+    std::fs::write(
+        path,
+        serde_json::to_string(&Trace {
+            trace_events: events,
+        })?,
+    )
+}
@@ -6,35 +6,212 @@
 
 use std::{
     borrow::Cow,
-    collections::{HashMap, HashSet},
+    collections::{hash_map::DefaultHasher, BTreeMap, BTreeSet, HashMap, HashSet},
     fs::File,
+    hash::{Hash, Hasher},
     io::{Error, Read, Write},
     path::{Path, PathBuf},
     process::{Command, Output, Stdio},
     str::FromStr,
+    time::SystemTime,
 };
 
-use crate::{latex, sage, Options};
+use crate::{
+    buildlog,
+    config::{self, DocumentConfig},
+    count, dtx, flatten, fonts, glossaries, gnuplot, group, latex,
+    lock::BuildLock,
+    message, packages, pdfa, profile, rmarkdown, sage, scan,
+    util::{self, hash_file, shell_quote, TempDir},
+    view, Options, OutFmt, OutputDirMode,
+};
+
+/// Source of recipes
+///
+/// Implement this to add recipes beyond the builtin ones when using latexmk as a library. Each
+/// one starts from [`Recipe::new`].
+pub trait RecipeProvider {
+    /// Add recipes to `map`, keyed by the file extension they produce
+    fn register(&self, options: &Options, map: &mut HashMap<String, Recipe>);
+}
+
+/// Bibtex recipe
+pub struct Bibtex;
+
+impl RecipeProvider for Bibtex {
+    fn register(&self, options: &Options, map: &mut HashMap<String, Recipe>) {
+        let mut script = String::from("bibtex");
+        for arg in options.bibtex_args.iter() {
+            script.push(' ');
+            script.push_str(&shell_quote(arg).replace('%', "%%"));
+        }
+        script.push_str(" %N");
+        map.insert(
+            "bbl".into(),
+            Recipe {
+                uses: "aux",
+                extras: &["bib"],
+                generated: &["blg", "bbl.partial"],
+                script: script.into(),
+                extra_deps: Some(bibtex_deps),
+                input_hash: Some(citations_hash),
+                requires: &["bibtex"],
+                atomic: true,
+                ..Recipe::default()
+            },
+        );
+    }
+}
+
+/// The `.bib` and `.bst` files named in `aux`
+///
+/// Styles from the TeX distribution aren't found, but they rarely change anyway
+fn bibtex_deps(aux: &Path) -> Vec<PathBuf> {
+    let contents = std::fs::read_to_string(aux).unwrap_or_default();
+    // When the aux is in the output directory, the sources are where latex was run from
+    let source_dir = parse_fls(&aux.with_extension("fls"))
+        .ok()
+        .and_then(|fls| fls.pwd.into_iter().next());
+    let mut ret = vec![];
+    for line in contents.lines() {
+        let (names, ext) = if let Some(names) = line.strip_prefix("\\bibdata{") {
+            (names, "bib")
+        } else if let Some(names) = line.strip_prefix("\\bibstyle{") {
+            (names, "bst")
+        } else {
+            continue;
+        };
+        for name in names.trim_end().trim_end_matches('}').split(',') {
+            let name = format!("{}.{}", name.trim(), ext);
+            match &source_dir {
+                Some(dir) if !aux.with_file_name(&name).exists() => ret.push(dir.join(name)),
+                _ => ret.push(aux.with_file_name(name)),
+            }
+        }
+    }
+    ret
+}
 
-fn make_cmds(options: &Options) -> HashMap<String, Recipe> {
+/// Hash of the lines of `aux` bibtex reads, following the `\@input` of included files
+///
+/// The rest of the `.aux` changes on every pass, but only a change to the citations, databases
+/// or style means the bibliography has to be rebuilt
+fn citations_hash(aux: &Path) -> Option<u64> {
+    fn collect(aux: &Path, lines: &mut Vec<String>, depth: usize) -> Option<()> {
+        let contents = std::fs::read_to_string(aux).ok()?;
+        for line in contents.lines() {
+            if let Some(included) = line
+                .strip_prefix("\\@input{")
+                .and_then(|l| l.strip_suffix('}'))
+            {
+                // Bounded, in case the files include each other
+                if depth < 16 {
+                    let _ = collect(&aux.with_file_name(included), lines, depth + 1);
+                }
+            } else if ["\\citation{", "\\bibdata{", "\\bibstyle{"]
+                .iter()
+                .any(|prefix| line.starts_with(prefix))
+            {
+                lines.push(line.into());
+            }
+        }
+        Some(())
+    }
+    let mut lines = vec![];
+    collect(aux, &mut lines, 0)?;
+    let mut hasher = DefaultHasher::new();
+    lines.hash(&mut hasher);
+    Some(hasher.finish())
+}
+
+/// The recipes latexmk ships with
+pub fn builtin_providers() -> Vec<Box<dyn RecipeProvider>> {
+    vec![
+        Box::new(latex::Latex),
+        Box::new(sage::Sage),
+        Box::new(Bibtex),
+        Box::new(glossaries::Glossaries),
+    ]
+}
+
+fn make_cmds(options: &Options, providers: &[Box<dyn RecipeProvider>]) -> HashMap<String, Recipe> {
     let mut map = HashMap::new();
-    latex::make_cmds(options, &mut map);
-    sage::make_cmds(options, &mut map);
-    // bibtex
-    map.insert(
-        "bbl".into(),
-        Recipe {
-            uses: "aux",
-            extras: &["bib"],
-            generated: &["blg"],
-            generated_dirs: &[],
-            script: "bibtex \"%N\"".into(),
-        },
-    );
-    // use make
+    for provider in providers {
+        provider.register(options, &mut map);
+    }
     map
 }
 
+/// Error for a build without a recipe making the output format `base`
+fn no_engine(base: &str) -> Error {
+    Error::other(format!(
+        "no recipe makes `{}`, so the documents can't be built",
+        base
+    ))
+}
+
+/// The recipe making the output format `base` from the document
+fn engine_recipe<'a>(
+    recipes: &'a HashMap<String, Recipe>,
+    base: &str,
+) -> std::io::Result<&'a Recipe> {
+    recipes.get(base).ok_or_else(|| no_engine(base))
+}
+
+/// Remove the recipes left out by `--enable-recipe` & `--disable-recipe` from `recipes`
+///
+/// The engine, which reads the document itself, is always kept. Recipes reading what a removed
+/// one makes are pointed out, since they only run if it's already there.
+fn select_recipes(options: &Options, recipes: &mut HashMap<String, Recipe>) {
+    for (option, exts) in [
+        ("--enable-recipe", &options.enable_recipe),
+        ("--disable-recipe", &options.disable_recipe),
+    ] {
+        for ext in exts.iter().filter(|ext| !recipes.contains_key(*ext)) {
+            message!(
+                "{} {}: there's no recipe making `{}`",
+                util::yellow("Warning:"),
+                option,
+                ext
+            );
+        }
+    }
+    let removed: Vec<String> = recipes
+        .iter()
+        .filter(|(makes, _)| {
+            options.disable_recipe.contains(makes)
+                || !(options.enable_recipe.is_empty() || options.enable_recipe.contains(makes))
+        })
+        .filter(|(makes, recipe)| {
+            let engine = recipe.uses == "tex";
+            if engine && options.disable_recipe.contains(makes) {
+                message!(
+                    "{} --disable-recipe: `{}` is made by {}, which can't be disabled",
+                    util::yellow("Warning:"),
+                    makes,
+                    recipe.program()
+                );
+            }
+            !engine
+        })
+        .map(|(makes, _)| makes.clone())
+        .collect();
+    for makes in removed.iter() {
+        recipes.remove(makes);
+    }
+    for makes in removed.iter() {
+        for other in recipes.keys().filter(|other| recipes[*other].uses == makes) {
+            message!(
+                "{} the recipe making `{}` reads the `{}` of the disabled recipe, so it only \
+                 runs if that's already there",
+                util::yellow("Warning:"),
+                other,
+                makes
+            );
+        }
+    }
+}
+
 /// Dependencies
 #[derive(Debug, Default)]
 pub struct Deps {
@@ -44,17 +221,38 @@ pub struct Deps {
     output: HashSet<PathBuf>,
     /// Files reported as missing
     missing: HashSet<String>,
+    /// Packages reported as not installed
+    packages: HashSet<String>,
+    /// Output of each command that was run, as `(file, output)`
+    log: Vec<(String, String)>,
 }
 
 impl Deps {
+    /// Record a generated file, so it's removed by clean
+    pub(crate) fn add_output(&mut self, path: PathBuf) {
+        self.output.insert(path);
+    }
+
+    /// Record the missing files & packages reported in `log`, as well as the ones printed
+    fn add_log_notes(&mut self, log: &str) {
+        self.missing.extend(find(log));
+        self.packages.extend(packages::missing_packages(log));
+    }
+
     /// Clear the input and missing file lists
     fn clear(&mut self) {
         self.input.clear();
         self.missing.clear();
+        self.packages.clear();
     }
 }
 
 /// Recipe struct
+///
+/// Fields are added as new features need them, so outside of this crate it's made with
+/// [`Recipe::new`], and the other fields are set afterwards
+#[derive(Default)]
+#[non_exhaustive]
 pub struct Recipe {
     /// The input file extension
     pub uses: &'static str,
@@ -71,7 +269,145 @@ pub struct Recipe {
     /// - `%I`: The input file name
     /// - `%N`: The filename without an extension
     /// - `%%`: A literal percent
+    ///
+    /// File names are quoted for the shell, so they shouldn't be quoted in the script
     pub script: Cow<'static, str>,
+    /// Environment variables to set when running the script
+    pub env: Vec<(&'static str, String)>,
+    /// Finds extra files used when running, given the input file
+    ///
+    /// Like `extras`, these are used when determining the file modification times
+    pub extra_deps: Option<fn(&Path) -> Vec<PathBuf>>,
+    /// Extra files used when running that are known up front, e.g. from the options
+    pub extra_files: Vec<PathBuf>,
+    /// Directory to run the script in
+    ///
+    /// Defaults to the directory containing the file, which is what relative paths in the
+    /// document are resolved against
+    pub work_dir: Option<PathBuf>,
+    /// Extensions of the other outputs the script writes, besides the one it's registered as
+    /// making
+    ///
+    /// Needing several of them only runs the script once
+    pub produces: &'static [&'static str],
+    /// Extensions of outputs that clean should keep, since they're slow to regenerate
+    pub precious: &'static [&'static str],
+    /// Hashes the part of the input the script actually reads
+    ///
+    /// When the input is newer than the output, but this hash matches the one the build log
+    /// recorded for the last run, the recipe isn't run again
+    pub input_hash: Option<fn(&Path) -> Option<u64>>,
+    /// Checks whether a run asks for the script to be run again, given the output it made and
+    /// what it printed
+    ///
+    /// Like the engine, it's run up to `--max-passes` times
+    pub rerun: Option<fn(&Path, &Output) -> bool>,
+    /// Most times the script may run while building a document, or `None` for no limit of its
+    /// own besides `--max-passes`
+    ///
+    /// `--max-runs` overrides it
+    pub max_runs: Option<usize>,
+    /// Checks each line the script prints while it's running, returning a message to show
+    /// right away, e.g. that it's going to take a while
+    ///
+    /// Each message is shown once per run
+    pub progress: Option<fn(&str) -> Option<&'static str>>,
+    /// The programs the script runs, which have to be installed
+    pub requires: &'static [&'static str],
+    /// Whether the output is only kept once the script succeeds
+    ///
+    /// `%O` is replaced by a temporary name, which is renamed to the output afterwards. Scripts
+    /// that name the output themselves leave a marker next to it instead, and a failed or
+    /// interrupted run removes the output, so the next build makes it again rather than reading
+    /// half of it.
+    pub atomic: bool,
+}
+
+/// Error for a recipe whose program isn't installed
+#[derive(Debug)]
+pub struct ToolNotFound(pub String);
+
+impl std::fmt::Display for ToolNotFound {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} not found, is it installed?", self.0)
+    }
+}
+
+impl std::error::Error for ToolNotFound {}
+
+/// Whether the file name `path_name` is `stem`, or `stem` with extensions
+fn is_named_after(path_name: &str, stem: &str) -> bool {
+    !stem.is_empty()
+        && path_name
+            .strip_prefix(stem)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('.'))
+}
+
+/// Whether `name` is the file with the extension `ext` a recipe generates for `path_name`
+fn is_generated_file(name: &str, ext: &str, path_name: &str) -> bool {
+    name.strip_suffix(ext)
+        .and_then(|n| n.strip_suffix('.'))
+        .is_some_and(|stem| is_named_after(path_name, stem))
+}
+
+/// Whether `name` is the directory starting with `prefix` a recipe generates for `path_name`
+///
+/// These are named after the document, like `_minted-paper`, or shared by every document in the
+/// directory, like `_minted`
+fn is_generated_dir(name: &str, prefix: &str, path_name: &str) -> bool {
+    name.strip_prefix(prefix).is_some_and(|rest| {
+        let rest = rest.strip_prefix('-').unwrap_or(rest);
+        // sagetex names its directory after the source, e.g. `sage-plots-for-paper.tex`
+        let stem = Path::new(rest)
+            .file_stem()
+            .map_or("", |s| s.to_str().unwrap_or(""));
+        rest.is_empty() || is_named_after(path_name, rest) || is_named_after(path_name, stem)
+    })
+}
+
+/// Where an atomic recipe writes its output to `path` until it's done
+fn partial_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".partial");
+    path.with_file_name(name)
+}
+
+/// The name of `path` without the extension `ext`
+///
+/// When given the source instead of an output, this is just its file stem, so the recipe is
+/// always run
+fn output_stem<'a>(path: &'a Path, ext: &str) -> &'a str {
+    let name = path.file_name().map_or("", |o| o.to_str().unwrap_or(""));
+    name.strip_suffix(ext)
+        .and_then(|n| n.strip_suffix('.'))
+        .unwrap_or_else(|| path.file_stem().map_or("", |o| o.to_str().unwrap_or("")))
+}
+
+/// Replace the placeholders in `script` with the shell quoted `output`, `input` and `stem`
+///
+/// The script is read left to right, so `%%` is always a literal percent, even when followed by
+/// the letter of a placeholder, and the names themselves are never substituted into
+fn substitute(script: &str, output: &str, input: &str, stem: &str) -> String {
+    let mut ret = String::with_capacity(script.len());
+    let mut chars = script.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            ret.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('O') => ret.push_str(&shell_quote(output)),
+            Some('I') => ret.push_str(&shell_quote(input)),
+            Some('N') => ret.push_str(&shell_quote(stem)),
+            Some('%') => ret.push('%'),
+            Some(c) => {
+                ret.push('%');
+                ret.push(c);
+            }
+            None => ret.push('%'),
+        }
+    }
+    ret
 }
 
 /// Calculates the parent of a given path
@@ -88,99 +424,277 @@ fn with_parent<W>(path: &Path, f: impl FnOnce(&Path) -> W) -> W {
 }
 
 impl Recipe {
+    /// Recipe running `script` on the file with the extension `uses`, with the defaults for
+    /// everything else
+    pub fn new(uses: &'static str, script: impl Into<Cow<'static, str>>) -> Self {
+        Self {
+            uses,
+            script: script.into(),
+            ..Self::default()
+        }
+    }
+
+    /// The program the script runs
+    pub fn program(&self) -> &str {
+        self.script.split_whitespace().next().unwrap_or("")
+    }
+
+    /// Fail with [`ToolNotFound`] if one of the programs it requires isn't installed
+    pub fn check_installed(&self) -> std::io::Result<()> {
+        match self
+            .requires
+            .iter()
+            .find(|tool| util::find_program(tool).is_none())
+        {
+            Some(tool) => Err(Error::new(
+                std::io::ErrorKind::NotFound,
+                ToolNotFound(tool.to_string()),
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// Fail with [`ToolNotFound`] if the shell couldn't find the program
+    fn check_found(&self, output: &Output) -> std::io::Result<()> {
+        // The status bash uses for commands that don't exist
+        if output.status.code() == Some(127) {
+            Err(Error::new(
+                std::io::ErrorKind::NotFound,
+                ToolNotFound(self.program().into()),
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
     /// Compare file modification times
-    pub fn check_file_times(
-        &self,
-        input_name: &PathBuf,
-        output_name: &str,
-    ) -> std::io::Result<bool> {
+    ///
+    /// Returns true if `output` is newer than `input_name` and the extra files next to it
+    pub fn check_file_times(&self, output: &PathBuf, input_name: &str) -> std::io::Result<bool> {
         // Check file times and only rebuild if needed
-        let output_time = File::open(input_name.with_file_name(output_name))?
-            .metadata()?
-            .modified()?;
-        let input_time = File::open(input_name)?.metadata()?.modified()?;
-        for path in PathBuf::from_str(".").unwrap().read_dir()? {
+        let output_time = File::open(output)?.metadata()?.modified()?;
+        let input = output.with_file_name(input_name);
+        let input_time = File::open(&input)?.metadata()?.modified()?;
+        let extra_deps = self.extra_deps.map(|f| f(&input)).unwrap_or_default();
+        if extra_deps
+            .iter()
+            .chain(self.extra_files.iter())
+            .any(|dep| util::modified(dep).is_some_and(|t| t > output_time))
+        {
+            return Ok(false);
+        }
+        for path in with_parent(output, |p| p.read_dir())? {
             let path = path?;
             let name = path.file_name();
             let name = name.to_str().unwrap_or("");
             for extra in self.extras.iter() {
-                if name.ends_with(extra) {
-                    if output_time > path.metadata()?.modified()? {
-                        return Ok(true);
-                    }
+                if name.ends_with(extra) && output_time < path.metadata()?.modified()? {
+                    return Ok(false);
+                }
+            }
+        }
+        if output_time > input_time {
+            return Ok(true);
+        }
+        // The input was rewritten, but the part the script reads may be the same
+        Ok(self.input_hash.is_some_and(|hash| {
+            hash(&input).is_some_and(|h| buildlog::input_hash(&input) == Some(h))
+        }))
+    }
+
+    /// Whether `path` is inside one of the generated directories
+    pub fn in_generated_dir(&self, path: &Path) -> bool {
+        path.ancestors().skip(1).any(|dir| {
+            dir.file_name()
+                .and_then(|n| n.to_str())
+                .is_some_and(|n| self.generated_dirs.iter().any(|gen| n.starts_with(gen)))
+        })
+    }
+
+    /// Add the generated files & directories next to `path` to `output`
+    ///
+    /// Only the ones named after `path` are added, e.g. `paper.css` but not `style.css` for
+    /// `paper.tex`, since other files with those extensions may belong to the user
+    pub fn collect_generated(&self, path: &Path, output: &mut HashSet<PathBuf>) {
+        let path_name = path.file_name().map_or("", |o| o.to_str().unwrap_or(""));
+        if let Ok(dir) = with_parent(path, |f| f.read_dir()) {
+            for file in dir.flatten() {
+                let name = file.file_name();
+                let name = name.to_str().unwrap_or("");
+                let generated = if file.file_type().is_ok_and(|f| f.is_dir()) {
+                    self.generated_dirs
+                        .iter()
+                        .any(|gen| is_generated_dir(name, gen, path_name))
+                } else {
+                    self.generated
+                        .iter()
+                        .any(|gen| is_generated_file(name, gen, path_name))
+                };
+                if generated {
+                    output.insert(file.path());
                 }
             }
         }
-        Ok(output_time > input_time)
     }
 
     /// Run recipe for the provided path
+    ///
+    /// Returns `None` if the output is already up to date
     pub fn on_file(
         &self,
         path: &PathBuf,
         ext: &str,
         output: &mut HashSet<PathBuf>,
-    ) -> std::io::Result<Output> {
-        if let Ok(dir) = with_parent(path, |f| f.read_dir()) {
-            for file in dir {
-                if let Ok(file) = file {
-                    if file.file_type().map_or(false, |f| f.is_dir()) {
-                        let name = file.file_name();
-                        let name = name.to_str().unwrap_or("");
-                        if self.generated_dirs.iter().any(|gen| name.starts_with(gen)) {
-                            output.insert(file.path());
-                        }
-                    } else {
-                        let name = file.file_name();
-                        let name = name.to_str().unwrap_or("");
-                        if self.generated.iter().any(|gen| name.ends_with(gen)) {
-                            output.insert(file.path());
-                        }
-                    }
-                }
-            }
+    ) -> std::io::Result<Option<Output>> {
+        self.collect_generated(path, output);
+        if !self.needs_run(path, ext) {
+            return Ok(None);
         }
-        let output_name = path.file_name().map_or("", |o| o.to_str().unwrap_or(""));
-        let input_name = format!(
-            "{}.{}",
-            &output_name[..output_name.len() - ext.len() - 1],
-            self.uses
-        );
-        println!("Running rule on {}", input_name);
+        message!("Running rule on {}.{}", output_stem(path, ext), self.uses);
+        self.run(path, ext, output).map(Some)
+    }
 
+    /// Whether the output at `path` is out of date, and its input exists
+    fn needs_run(&self, path: &PathBuf, ext: &str) -> bool {
+        let input_name = format!("{}.{}", output_stem(path, ext), self.uses);
+        if self.atomic && partial_path(path).exists() {
+            // The last run never finished, e.g. because the build was killed
+            return path.with_file_name(&input_name).exists();
+        }
         // Note that this function will fail with an error if the file doesn't exist, but there
         // is not harm is rebuilding the file if we don't need to.
-        if matches!(self.check_file_times(&path, &output_name), Ok(true))
-            || !path.with_file_name(&input_name).exists()
-        {
-            return Command::new("true").output();
-        }
+        (!matches!(self.check_file_times(path, &input_name), Ok(true)) || buildlog::failed(path))
+            && path.with_file_name(&input_name).exists()
+    }
 
+    /// Run the script for `path`, whether or not it's up to date
+    fn run(
+        &self,
+        path: &Path,
+        ext: &str,
+        output: &mut HashSet<PathBuf>,
+    ) -> std::io::Result<Output> {
+        let partial = partial_path(path);
+        let output_name = if self.atomic {
+            partial.file_name()
+        } else {
+            path.file_name()
+        };
+        let output_name = output_name.map_or("", |o| o.to_str().unwrap_or(""));
+        let stem = output_stem(path, ext);
+        let input_name = format!("{}.{}", stem, self.uses);
+        if self.atomic && !self.script.contains("%O") {
+            File::create(&partial)?;
+        }
         let mut cmd = Command::new("bash");
-        cmd.arg("-c").arg(
-            self.script
-                .replace("%O", output_name)
-                .replace("%I", &input_name)
-                .replace("%N", &output_name[..output_name.len() - ext.len() - 1])
-                .replace("%%", "%"),
-        );
-        if let Some(parent) = path.parent() {
+        cmd.arg("-c")
+            .arg(substitute(&self.script, output_name, &input_name, stem));
+        cmd.envs(self.env.iter().map(|(k, v)| (k, v)));
+        // The output is captured, so nobody would see a prompt, even from a terminal. Reading
+        // gets an end of file instead, and the engine gives up rather than waiting forever.
+        cmd.stdin(Stdio::null());
+        if let Some(dir) = &self.work_dir {
+            cmd.current_dir(dir);
+        } else if let Some(parent) = path.parent() {
             if let Ok(dir) = parent.canonicalize() {
                 cmd.current_dir(dir);
             }
         }
-        cmd.stdout(Stdio::piped()).output()
+        let ret = profile::span(&format!("{} {}", self.program(), input_name), || match self
+            .progress
+        {
+            Some(progress) => {
+                let mut shown = vec![];
+                util::output_with(&mut cmd, |line| {
+                    if let Some(msg) = progress(line).filter(|m| !shown.contains(m)) {
+                        message!("{}", msg);
+                        shown.push(msg);
+                    }
+                })
+            }
+            None => util::output(cmd.stdout(Stdio::piped())),
+        })?;
+        if self.atomic {
+            self.finish_partial(path, &partial, ret.status.success())?;
+        }
+        // Pick up the files that didn't exist before running
+        self.collect_generated(path, output);
+        // The engine is given its source rather than its output, which is recorded by the build
+        if path
+            .to_str()
+            .is_some_and(|p| p.ends_with(&format!(".{}", ext)))
+        {
+            buildlog::record_outcome(path, ret.status.success())?;
+        }
+        if let Some(hash) = self.input_hash.filter(|_| ret.status.success()) {
+            let input = path.with_file_name(&input_name);
+            if let Some(hash) = hash(&input) {
+                buildlog::record_input_hash(&input, hash)?;
+            }
+        }
+        Ok(ret)
+    }
+
+    /// Keep the output of a run of an atomic recipe if it succeeded, or remove what it left if
+    /// it didn't
+    fn finish_partial(&self, path: &Path, partial: &Path, success: bool) -> std::io::Result<()> {
+        let remove = |path: &Path| match std::fs::remove_file(path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        };
+        if !self.script.contains("%O") {
+            if !success {
+                remove(path)?;
+            }
+            remove(partial)
+        } else if success && partial.exists() {
+            std::fs::rename(partial, path)
+        } else {
+            remove(partial)
+        }
     }
 
     /// Run recipe for the provided path
     pub fn run_for(&self, path: &PathBuf, ext: &str, deps: &mut Deps) -> std::io::Result<()> {
-        let output = self.on_file(path, ext, &mut deps.output)?;
+        let output = match self.on_file(path, ext, &mut deps.output)? {
+            Some(output) => output,
+            None => return Ok(()),
+        };
+        self.check_found(&output)?;
         let stdout = String::from_utf8_lossy(&output.stdout);
         deps.missing = find(&stdout);
+        deps.packages.extend(packages::missing_packages(&stdout));
+        deps.log
+            .push((util::display_path(path), log_entry(&output)));
         if !output.status.success() {
-            println!("Failed to build {}", path.display());
-            std::io::stdout().write_all(&output.stdout)?;
-            std::io::stdout().write_all(&output.stderr)?;
+            // This is how TeX reports it couldn't open the output, e.g. because it's locked
+            if let Some(locked) = stdout
+                .split("I can't write on file `")
+                .nth(1)
+                .and_then(|rest| rest.split('\'').next())
+            {
+                print_locked(locked);
+            }
+            let mut out = util::messages();
+            if killed(&output) {
+                // Not a LaTeX error, so the log is no help
+                let message = format!(
+                    "{} terminated abnormally building {}",
+                    self.program(),
+                    util::display_path(path)
+                );
+                writeln!(out, "{}", util::red(&message))?;
+                out.write_all(&output.stderr)?;
+                return Err(Error::new(std::io::ErrorKind::Interrupted, message));
+            }
+            writeln!(
+                out,
+                "{} {}",
+                util::red("Failed to build"),
+                util::display_path(path)
+            )?;
+            out.write_all(util::engine_log(&output.stdout).as_bytes())?;
+            out.write_all(&output.stderr)?;
             Err(file_error("Failed to make"))
         } else {
             Ok(())
@@ -188,15 +702,233 @@ impl Recipe {
     }
 }
 
+/// Whether the program was killed, e.g. by a crash or Ctrl-C, rather than exiting by itself
+///
+/// bash reports a command killed by a signal with a status of 128 plus the signal number
+fn killed(output: &Output) -> bool {
+    output
+        .status
+        .code()
+        .is_none_or(|code| (129..=128 + 64).contains(&code))
+}
+
+/// Everything `output` printed, for the build log
+fn log_entry(output: &Output) -> String {
+    let mut entry = String::from_utf8_lossy(&output.stdout).into_owned();
+    entry.push_str(&String::from_utf8_lossy(&output.stderr));
+    entry
+}
+
+/// Write the output of every command that was run to `path`, numbering the passes over each file
+fn write_log(path: &Path, log: &[(String, String)]) -> std::io::Result<()> {
+    let mut out = File::create(path)?;
+    for (i, (file, entry)) in log.iter().enumerate() {
+        let pass = log[..i].iter().filter(|(f, _)| f == file).count() + 1;
+        writeln!(out, "==> {} (pass {}) <==", file, pass)?;
+        out.write_all(entry.as_bytes())?;
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+/// Checks whether `output` is newer than `tex` and every input recorded by the last run, whose
+/// intermediate files are at `job`
+///
+/// Files the engine writes itself (e.g. the `.aux`) are skipped, since they are always rewritten
+/// after being read. Instead, the log of the last run must not ask for another pass, and no recipe
+/// may have failed or been interrupted while making the output or an intermediate file.
+fn up_to_date(tex: &Path, job: &Path, scanned: &[PathBuf], output: &Path) -> bool {
+    let output_time = match util::modified(output) {
+        Some(time) => time,
+        None => return false,
+    };
+    let inputs_older = recorded_inputs(tex, job, scanned)
+        .iter()
+        .all(|input| util::modified(input).is_some_and(|t| t < output_time));
+    let log = job.with_extension("log");
+    inputs_older
+        && log.exists()
+        && !rerun_requested(&log)
+        && !has_partial(job)
+        && !buildlog::job_failed(job)
+}
+
+/// Whether an atomic recipe left an unfinished intermediate file of the job at `job`
+fn has_partial(job: &Path) -> bool {
+    let stem = job.file_stem().map_or("", |o| o.to_str().unwrap_or(""));
+    let prefix = format!("{}.", stem);
+    with_parent(job, |dir| dir.read_dir()).is_ok_and(|dir| {
+        dir.flatten().any(|file| {
+            let name = file.file_name();
+            let name = name.to_str().unwrap_or("");
+            name.starts_with(&prefix) && name.ends_with(".partial")
+        })
+    })
+}
+
+/// `tex` and the inputs recorded by the last run, whose intermediate files are at `job`
+fn recorded_inputs(tex: &Path, job: &Path, scanned: &[PathBuf]) -> Vec<PathBuf> {
+    let mut recorded = Deps::default();
+    // The `.fls` may not exist yet, and some files (e.g. pgfplots tables) aren't always recorded,
+    // so the scanned dependencies are checked as well. These are only a guess, so missing ones are
+    // ignored.
+    let _ = collect_files(&job.with_extension("fls"), &mut recorded);
+    recorded
+        .input
+        .extend(scanned.iter().filter(|p| p.exists()).cloned());
+    recorded
+        .input
+        .iter()
+        .filter(|input| !recorded.output.contains(*input))
+        .cloned()
+        .chain(std::iter::once(tex.to_path_buf()))
+        .collect()
+}
+
+/// Resolution of the coarsest timestamps in use, those of FAT
+const COARSE_TIMESTAMPS: std::time::Duration = std::time::Duration::from_secs(2);
+
+/// Make sure `output` is newer than the inputs it was just built from
+///
+/// On filesystems with coarse timestamps (e.g. 2 seconds on FAT) an input written just before
+/// the build can have the same time as the output, which would make the next run rebuild it.
+/// An input that's newer by more than that was saved during the build, so it's left newer, and
+/// the next run rebuilds with it.
+fn mark_current(tex: &Path, job: &Path, scanned: &[PathBuf], output: &Path) -> std::io::Result<()> {
+    let output_time = match util::modified(output) {
+        Some(time) => time,
+        None => return Ok(()),
+    };
+    let newest = recorded_inputs(tex, job, scanned)
+        .iter()
+        .filter_map(|input| util::modified(input))
+        .max();
+    let newest = match newest {
+        Some(newest) => newest,
+        None => return Ok(()),
+    };
+    // Times without a fraction of a second are all a coarse filesystem records
+    let whole = |t: SystemTime| {
+        t.duration_since(SystemTime::UNIX_EPOCH)
+            .is_ok_and(|d| d.subsec_nanos() == 0)
+    };
+    let granularity = if whole(output_time) && whole(newest) {
+        COARSE_TIMESTAMPS
+    } else {
+        std::time::Duration::ZERO
+    };
+    match newest.duration_since(output_time) {
+        Ok(ahead) if ahead <= granularity => util::touch(output, newest + COARSE_TIMESTAMPS),
+        _ => Ok(()),
+    }
+}
+
+/// Add `file` to `files`, after the documents it reads labels from with `\externaldocument`
+///
+/// Documents that reference each other are added in the order they're found, since neither can
+/// go first. `visiting` holds the documents this one is being added for.
+fn with_external_documents(file: &Path, files: &mut Vec<PathBuf>, visiting: &mut Vec<PathBuf>) {
+    let key = file.canonicalize().unwrap_or_else(|_| file.to_path_buf());
+    let same = |f: &PathBuf| f.canonicalize().unwrap_or_else(|_| f.clone()) == key;
+    if files.iter().any(same) || visiting.contains(&key) {
+        return;
+    }
+    visiting.push(key);
+    for other in scan::external_documents(file) {
+        with_external_documents(&other, files, visiting);
+    }
+    visiting.pop();
+    files.push(file.to_path_buf());
+}
+
+/// Hash of the `.aux` files at `job`, or `None` if there's no main `.aux` yet
+///
+/// Each `\include`d file gets an `.aux` of its own, which is found from the outputs in the `.fls`
+fn aux_hash(job: &Path) -> Option<u64> {
+    let aux = job.with_extension("aux");
+    let mut hashes = vec![hash_file(&aux)?];
+    if let Ok(fls) = parse_fls(&job.with_extension("fls")) {
+        let main = aux.canonicalize().ok();
+        let mut seen = HashSet::new();
+        for output in fls.output {
+            if output.extension().is_some_and(|ext| ext == "aux")
+                && output.canonicalize().ok() != main
+                && seen.insert(output.clone())
+            {
+                hashes.push(hash_file(&output).unwrap_or_default());
+            }
+        }
+    }
+    let mut hasher = DefaultHasher::new();
+    hashes.hash(&mut hasher);
+    Some(hasher.finish())
+}
+
+/// Run the engine `recipe` once on `file`
+///
+/// Nothing is printed in batchmode, so for a document in it the missing files are read from
+/// `batch_log` instead
+fn engine_pass(
+    options: &Options,
+    recipe: &Recipe,
+    file: &PathBuf,
+    base: &str,
+    batch_log: Option<&Path>,
+    deps: &mut Deps,
+) -> std::io::Result<()> {
+    let result = recipe.run_for(file, base, deps);
+    if let Some(log) = batch_log {
+        let contents = std::fs::read(log).unwrap_or_default();
+        deps.add_log_notes(&String::from_utf8_lossy(&contents));
+        if result.is_err() {
+            message!("The errors are in {}", options.display_path(log));
+        }
+    }
+    result
+}
+
+/// Whether the pass that just ran at `job` has to be followed by another
+///
+/// References have settled once a pass leaves the `.aux` files as they were before it, whose
+/// hash is `previous`. Without one there's nothing to compare against, so the log is trusted.
+fn needs_another_pass(job: &Path, previous: Option<u64>) -> bool {
+    match previous {
+        Some(previous) => aux_hash(job) != Some(previous),
+        None => rerun_requested(&job.with_extension("log")),
+    }
+}
+
+/// Messages the kernel and packages print when another pass is needed
+const RERUN_MESSAGES: &[&str] = &[
+    "Rerun to get",
+    "Label(s) may have changed",
+    "Please rerun LaTeX",
+    "Rerun LaTeX",
+    "(rerunfilecheck)",
+];
+
+/// Whether the log at `log` asks for another pass
+///
+/// Comparing the `.aux` between passes is more reliable, so this is only used when there's
+/// nothing to compare against
+fn rerun_requested(log: &Path) -> bool {
+    std::fs::read_to_string(log).is_ok_and(|log| RERUN_MESSAGES.iter().any(|m| log.contains(m)))
+}
+
 /// Find `No file ` notes in output
 fn find(s: &str) -> HashSet<String> {
     let mut ret = HashSet::new();
     let mut cur = s;
-    while cur.len() > 0 {
+    while !cur.is_empty() {
         if let Some((_pre, rest)) = cur.split_once("No file ") {
-            let filename = rest.split_once('\n').map_or(rest, |(r, _)| r);
-            ret.insert(filename[..filename.len() - 1].into());
-            cur = &rest[1..];
+            let line = rest.split_once('\n').map_or(rest, |(r, _)| r);
+            // The name is given as it was looked up, with its extension, and ends the sentence
+            let filename = line.trim_end();
+            let filename = filename.strip_suffix('.').unwrap_or(filename);
+            if !filename.is_empty() {
+                ret.insert(filename.into());
+            }
+            cur = rest;
         } else {
             break;
         }
@@ -204,128 +936,2332 @@ fn find(s: &str) -> HashSet<String> {
     ret
 }
 
-/// Run commands to build recipe library, and run recipes as needed
-pub fn run_cmds(mut options: Options) -> std::io::Result<()> {
-    //eprintln!("{:?}", options);
-    let base = if options.dvi { "dvi" } else { "pdf" };
-
-    // Insert all files that end with .tex in the current directory if no files were specified
-    if options.files.len() == 0 {
-        let f = PathBuf::from_str(".").unwrap();
-        for file in f.read_dir()? {
-            let file = file?;
-            if file.file_name().to_str().unwrap().ends_with(".tex") {
-                options.files.push(file.path());
-            }
-        }
-    }
-
-    let recipes = make_cmds(&options);
-    let mut deps = Deps::default();
+/// Outcome of a build
+#[derive(Debug, Default)]
+pub struct BuildReport {
+    /// Whether every file was built successfully
+    pub success: bool,
+    /// The final output files that were produced
+    pub outputs: Vec<PathBuf>,
+    /// The files the outputs were built from
+    pub inputs: Vec<PathBuf>,
+    /// Files the last pass of the engine still reported missing, e.g. a `.bbl` that couldn't
+    /// be built
+    pub missing: Vec<PathBuf>,
+    /// Errors encountered while building
+    pub diagnostics: Vec<String>,
+    /// Whether the build failed because a program wasn't installed
+    pub tool_not_found: bool,
+}
 
-    for file in options.files {
-        let _ = recipes.get(base).unwrap().run_for(&file, base, &mut deps);
-        let name = file
-            .file_name()
-            .unwrap()
-            .to_str()
-            .expect("Unsupported filename");
-        collect_files(&name[..name.len() - ".tex".len()], &mut deps)?;
+fn is_tool_not_found(e: &Error) -> bool {
+    e.get_ref().is_some_and(|e| e.is::<ToolNotFound>())
+}
 
-        let mut rerun = false;
+/// Build the files specified by `options` using the builtin recipes
+pub fn build(options: &Options) -> BuildReport {
+    build_with(options, &builtin_providers())
+}
 
-        for dep in deps.input.iter() {
-            if build(dep, &mut deps.output, &recipes)? {
-                rerun = true;
-            }
+/// Same as [`build`], but using the recipes from `providers`
+///
+/// To keep the builtin recipes, include [`builtin_providers`] in `providers`
+pub fn build_with(options: &Options, providers: &[Box<dyn RecipeProvider>]) -> BuildReport {
+    let mut report = BuildReport::default();
+    if options.profile.is_some() {
+        profile::enable();
+    }
+    match run_cmds(options.clone(), providers, &mut report) {
+        Ok(()) => report.success = true,
+        Err(e) => {
+            report.tool_not_found = is_tool_not_found(&e);
+            report.diagnostics.push(e.to_string());
         }
-        for dep in deps.missing.iter() {
-            if build(
-                &PathBuf::default().with_file_name(&dep),
-                &mut deps.output,
-                &recipes,
-            )? {
-                rerun = true;
-            }
+    }
+    // Written even if the build failed, since that's often what's being looked into
+    if let Some(path) = &options.profile {
+        if let Err(e) = profile::write(path) {
+            report.success = false;
+            report.diagnostics.push(format!(
+                "Couldn't write {}: {}",
+                options.display_path(path),
+                e
+            ));
         }
+    }
+    report
+}
 
-        if rerun {
-            println!("Rerunning pdflatex");
-            recipes.get(base).unwrap().run_for(&file, base, &mut deps)?;
+/// Add `inputs` of `original` to the inputs of `report`
+///
+/// Inputs in the temporary directory are copies of the sources, so those are added instead
+fn add_report_inputs<'a>(
+    report: &mut BuildReport,
+    inputs: impl Iterator<Item = &'a PathBuf>,
+    tempdir: Option<&TempDir>,
+    original: &Path,
+) {
+    for input in inputs {
+        let input = match tempdir.map(|t| input.strip_prefix(&t.path)) {
+            Some(Ok(rest)) => with_parent(original, |p| p.join(rest)),
+            _ => input.clone(),
+        };
+        if !report.inputs.contains(&input) {
+            report.inputs.push(input);
         }
-        deps.clear();
     }
-    if options.clean {
-        println!("Cleaning up files");
-        for file in deps.output {
-            let name = file.file_name().map_or("", |s| s.to_str().unwrap_or(""));
-            // Protect pdf & dvi files
-            if !name.ends_with("pdf") && !name.ends_with("dvi") {
-                if let Err(_) = std::fs::remove_file(&file) {
-                    if let Err(_) = std::fs::remove_dir_all(&file) {
-                        println!("Couldn't remove {}", file.display());
-                    }
-                }
+}
+
+/// Add the images tex4ht generated for `file` to `output`
+///
+/// These are named after the file, e.g. `paper0x.png`
+fn register_tex4ht_images(file: &Path, output: &mut HashSet<PathBuf>) {
+    let stem = file.file_stem().map_or("", |s| s.to_str().unwrap_or(""));
+    if let Ok(dir) = with_parent(file, |p| p.read_dir()) {
+        for entry in dir.flatten() {
+            let name = entry.file_name();
+            let name = name.to_str().unwrap_or("");
+            let is_image = name
+                .strip_prefix(stem)
+                .and_then(|n| n.strip_suffix(".png").or_else(|| n.strip_suffix(".svg")))
+                .and_then(|n| n.strip_suffix('x'))
+                .is_some_and(|n| !n.is_empty() && n.chars().all(|c| c.is_ascii_digit()));
+            if is_image {
+                output.insert(entry.path());
             }
         }
     }
-    Ok(())
 }
 
-fn file_error(e: &'static str) -> Error {
-    std::io::Error::new(std::io::ErrorKind::InvalidData, e)
+/// Mark up the changes from `old` to `new` with latexdiff, writing `diff.tex` next to `new`
+///
+/// Documents split over several files are flattened first
+fn latexdiff(old: &Path, new: &Path) -> std::io::Result<PathBuf> {
+    let diff = with_parent(new, |p| p.join("diff.tex"));
+    message!(
+        "Running latexdiff on {} and {}",
+        util::display_path(old),
+        util::display_path(new)
+    );
+    let output = util::output(
+        Command::new("latexdiff")
+            .arg("--flatten")
+            .arg(old)
+            .arg(new)
+            .stderr(Stdio::inherit()),
+    )?;
+    if !output.status.success() {
+        return Err(file_error("latexdiff failed"));
+    }
+    std::fs::write(&diff, output.stdout)?;
+    Ok(diff)
 }
 
-fn collect_files(name: &str, deps: &mut Deps) -> std::io::Result<()> {
-    let mut r = File::open(format!("./{}.fls", name))?;
-    let mut s = String::new();
-    r.read_to_string(&mut s)?;
-    let mut pwd = PathBuf::from_str(".").unwrap();
-    for line in s.split('\n').filter(|s| s.trim() != "") {
-        let (cmd, file) = line
-            .trim()
-            .split_once(' ')
-            .ok_or(file_error("no space found"))?;
-        let mut path = PathBuf::from_str(file).map_err(|_| file_error("not a valid path"))?;
-        // make absolute if possible
-        if !path.is_absolute() {
-            path = pwd.join(path);
-        }
-        // Handle various possiblilities
-        if cmd == "PWD" {
-            pwd = path;
-        } else if cmd == "INPUT" {
-            deps.input.insert(path);
-        } else if cmd == "OUTPUT" {
-            deps.output.insert(path);
-        } else {
-            panic!("Unexpected line: {}", cmd);
+/// The files `git diff` reports as changed since `reference`, including uncommitted changes
+fn changed_since(reference: &str) -> std::io::Result<HashSet<PathBuf>> {
+    let git = |args: &[&str]| -> std::io::Result<String> {
+        let output = util::output(Command::new("git").args(args))?;
+        if !output.status.success() {
+            return Err(Error::other(format!(
+                "git {} failed: {}",
+                args.join(" "),
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
         }
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    };
+    // Names are relative to the top of the repository
+    let root = PathBuf::from(git(&["rev-parse", "--show-toplevel"])?.trim());
+    Ok(git(&["diff", "--name-only", reference, "--"])?
+        .lines()
+        .map(|name| {
+            let path = root.join(name);
+            // Deleted files can't be canonicalized, but they can't be matched to an input either
+            path.canonicalize().unwrap_or(path)
+        })
+        .collect())
+}
+
+/// Whether `file` reads any of the `changed` files, according to its last build
+fn needs_build_since(options: &Options, file: &Path, changed: &HashSet<PathBuf>) -> bool {
+    let job = in_output_dir(options, file);
+    if !job.with_extension("fls").exists() {
+        return true;
+    }
+    recorded_inputs(file, &job, &scan::scan_tex_deps(file))
+        .iter()
+        .filter_map(|input| input.canonicalize().ok())
+        .any(|input| changed.contains(&input))
+}
+
+/// Write the bibliography entries cited in `aux` to `bib`, for the document at `file`
+///
+/// bibexport runs bibtex, so it's run from the document's directory, with the same search
+/// paths as the bibtex recipe
+fn export_bib(
+    file: &Path,
+    aux: &Path,
+    bib: &Path,
+    env: &[(&'static str, String)],
+) -> std::io::Result<()> {
+    let mut cmd = Command::new("bibexport");
+    cmd.arg("-o")
+        .arg(std::env::current_dir()?.join(bib))
+        .arg(aux.canonicalize()?)
+        .envs(env.iter().map(|(k, v)| (k, v)));
+    cmd.current_dir(with_parent(file, Path::canonicalize)?);
+    let output = util::output(&mut cmd).map_err(|e| match e.kind() {
+        std::io::ErrorKind::NotFound => Error::new(e.kind(), ToolNotFound("bibexport".into())),
+        _ => e,
+    })?;
+    if !output.status.success() {
+        let mut out = util::messages();
+        writeln!(
+            out,
+            "{} {}",
+            util::red("Failed to export"),
+            util::display_path(bib)
+        )?;
+        out.write_all(&output.stdout)?;
+        out.write_all(&output.stderr)?;
+        return Err(file_error("bibexport failed"));
     }
     Ok(())
 }
 
-fn build(
-    dep: &PathBuf,
-    output: &mut HashSet<PathBuf>,
-    recipes: &HashMap<String, Recipe>,
-) -> std::io::Result<bool> {
-    let name = dep.file_name().map_or("", |o| o.to_str().unwrap_or(""));
-    //println!("Building {}", name);
-    for (makes, recipe) in recipes.iter() {
-        if name.ends_with(makes) {
-            output.insert(dep.clone());
-            let output = recipe.on_file(dep, makes, output)?;
-            if output.status.success() {
-                println!("Built {}", name);
-                return Ok(true);
-            } else {
-                println!("Failed to build {}", name);
-                std::io::stdout().write_all(&output.stdout)?;
-                std::io::stdout().write_all(&output.stderr)?;
-                return Ok(false);
+/// The settings shared by every document, from the project's and the user's config files
+fn shared_config(options: &Options) -> DocumentConfig {
+    let mut shared = DocumentConfig::default();
+    let paths = [
+        Some(PathBuf::from(config::PROJECT_FILE)),
+        config::global_path(),
+    ];
+    for path in paths.iter().flatten() {
+        match DocumentConfig::read(path) {
+            Ok(Some(config)) => {
+                if options.verbose {
+                    message!(
+                        "Using settings from {}: {}",
+                        options.display_path(path),
+                        config
+                    );
+                }
+                shared.merge(&config);
             }
+            Ok(None) => (),
+            Err(e) => message!("Ignoring settings in {}", e),
         }
     }
-    Ok(false)
+    shared
+}
+
+/// Apply the settings for `file`, from its `.latexmk.toml`, its magic comments and `shared`
+///
+/// Settings given on the command line take precedence, then the `.latexmk.toml`, then the
+/// magic comments and finally `shared`
+fn file_options(options: &Options, file: &Path, shared: &DocumentConfig) -> Options {
+    let mut options = options.clone();
+    let mut config = match DocumentConfig::load(file) {
+        Ok(Some(config)) => {
+            if options.verbose {
+                message!(
+                    "Using settings from {}: {}",
+                    options.display_path(&config::path_for(file)),
+                    config
+                );
+            }
+            config
+        }
+        Ok(None) => DocumentConfig::default(),
+        Err(e) => {
+            message!(
+                "Ignoring settings for {}: {}",
+                options.display_path(file),
+                e
+            );
+            DocumentConfig::default()
+        }
+    };
+    if options.engine.is_none() && config.engine.is_none() {
+        let program = scan::magic_comment(file, "program")
+            .or_else(|| scan::magic_comment(file, "TS-program"));
+        if let Some(program) = program {
+            match program.parse() {
+                Ok(engine) => options.engine = Some(engine),
+                Err(e) => message!("Ignoring program in {}: {}", options.display_path(file), e),
+            }
+        }
+    }
+    config.merge(shared);
+    if let Err(e) = config.apply(&mut options) {
+        message!(
+            "Ignoring settings for {}: {}",
+            options.display_path(file),
+            e
+        );
+    }
+    options
+}
+
+/// Run commands to build recipe library, and run recipes as needed
+fn run_cmds(
+    mut options: Options,
+    providers: &[Box<dyn RecipeProvider>],
+    report: &mut BuildReport,
+) -> std::io::Result<()> {
+    //eprintln!("{:?}", options);
+    util::init_color(options.color);
+    util::set_trace(options.trace);
+    util::set_raw_log(options.raw_log);
+    util::set_relative_paths(!options.no_relative_paths);
+    match config::Latexmkrc::load() {
+        Ok(Some((path, rc))) => {
+            if options.verbose {
+                for (line, code) in rc.ignored.iter() {
+                    message!(
+                        "Ignoring {}:{}: {}",
+                        options.display_path(&path),
+                        line,
+                        code.trim()
+                    );
+                }
+            } else if !rc.ignored.is_empty() {
+                message!(
+                    "{} ignoring {} lines of {} that aren't supported, see --verbose",
+                    util::yellow("Warning:"),
+                    rc.ignored.len(),
+                    options.display_path(&path)
+                );
+            }
+            match rc.apply(&mut options) {
+                Ok(warnings) => {
+                    for warning in warnings {
+                        message!(
+                            "{} {}: {}",
+                            util::yellow("Warning:"),
+                            options.display_path(&path),
+                            warning
+                        );
+                    }
+                }
+                Err(e) => message!(
+                    "Ignoring settings in {}: {}",
+                    options.display_path(&path),
+                    e
+                ),
+            }
+        }
+        Ok(None) => (),
+        Err(e) => message!("Ignoring settings in {}", e),
+    }
+    let format = options.output_format();
+    let base = format.ext();
+
+    // Recipes run in the directory of their input, so the output directory has to be absolute.
+    // Directories next to each source are resolved once the sources are known.
+    if let (Some(dir), OutputDirMode::Cwd) = (&options.output_dir, options.output_dir_mode) {
+        std::fs::create_dir_all(dir)?;
+        options.output_dir = Some(dir.canonicalize()?);
+    }
+
+    let mut deps = Deps::default();
+
+    if let [old, new] = &options.diff[..] {
+        let diff = latexdiff(old, new)?;
+        deps.output.insert(diff.clone());
+        options.files = vec![diff];
+    }
+
+    // Insert all files that end with .tex in the current directory if no files were specified
+    if options.files.is_empty() {
+        let f = PathBuf::from_str(".").unwrap();
+        if !options.no_respect_gitignore {
+            let walk = ignore::WalkBuilder::new(&f)
+                .max_depth(Some(1))
+                .hidden(false)
+                .build();
+            for file in walk {
+                let file = file.map_err(Error::other)?;
+                if file.depth() == 1 && dtx::is_document(file.path()) {
+                    options.files.push(file.into_path());
+                }
+            }
+        } else {
+            for file in f.read_dir()? {
+                let file = file?;
+                if dtx::is_document(&file.path()) {
+                    options.files.push(file.path());
+                }
+            }
+        }
+        // Chapters using the `subfiles` class are built as part of their main document
+        options
+            .files
+            .retain(|file| scan::subfiles_main(file).is_none());
+    }
+
+    if options.build_standalone {
+        // Build the figures first, so they're up to date when the documents include them
+        let mut files = vec![];
+        for file in options.files.iter() {
+            for figure in scan::standalone_deps(file) {
+                if !files.contains(&figure) && !options.files.contains(&figure) {
+                    files.push(figure);
+                }
+            }
+        }
+        files.append(&mut options.files);
+        options.files = files;
+    }
+
+    // Documents other ones take labels from need their `.aux` first
+    let mut files = vec![];
+    for file in std::mem::take(&mut options.files) {
+        with_external_documents(&file, &mut files, &mut vec![]);
+    }
+    options.files = files;
+
+    if let Some(since) = &options.since {
+        let changed = changed_since(since)?;
+        let files = std::mem::take(&mut options.files);
+        for file in files {
+            if needs_build_since(&options, &file, &changed) {
+                options.files.push(file);
+            } else {
+                message!(
+                    "Skipping {}, unchanged since {}",
+                    options.display_path(&file),
+                    since
+                );
+            }
+        }
+    }
+
+    for file in options.files.iter().filter(|f| !deps.output.contains(*f)) {
+        report.inputs.push(file.clone());
+    }
+
+    if options.group_output {
+        for dir in output_dirs(&options) {
+            group::restore(&dir)?;
+        }
+    }
+
+    let shared = shared_config(&options);
+    if options.plan || options.plan_json {
+        return print_plan(&options, providers, &shared);
+    }
+
+    if options.clean_output_dir {
+        return clean_output_dirs(&options);
+    }
+
+    if let Some(output) = &options.flatten {
+        let file = match &options.files[..] {
+            [file] => file,
+            _ => return Err(Error::other("--flatten needs a single document")),
+        };
+        flatten::flatten(file, output, options.flatten_graphics)?;
+        message!("Wrote {}", options.display_path(output));
+        return Ok(());
+    }
+
+    if options.count_only {
+        return print_counts(&options);
+    }
+
+    // The recipes for intermediate files, with their programs, and the ones that were needed
+    let mut registered = BTreeMap::new();
+    let mut used = HashSet::new();
+    for original in options.files.iter() {
+        let name = original
+            .file_name()
+            .and_then(|n| n.to_str())
+            .ok_or_else(|| {
+                Error::other(format!(
+                    "unsupported file name {}",
+                    options.display_path(original)
+                ))
+            })?;
+        let mut file_options = file_options(&options, original, &shared);
+        let final_output = in_output_dir(&options, original).with_extension(base);
+        if let Some(dir) = options.output_dir_for(original) {
+            std::fs::create_dir_all(&dir)?;
+            file_options.output_dir = Some(dir.canonicalize()?);
+        }
+        let _lock = if options.no_lock {
+            None
+        } else {
+            Some(BuildLock::acquire(&options, &final_output)?)
+        };
+        if let Some(hook) = &options.pre_build {
+            if !run_hook(hook, original, &final_output)? {
+                return Err(Error::other(format!(
+                    "--pre-build command failed for {}",
+                    options.display_path(original)
+                )));
+            }
+        }
+        // Build a copy of the source directory, so only the final output is touched
+        let tempdir = if options.tempdir {
+            let stem = original
+                .file_stem()
+                .map_or("", |s| s.to_str().unwrap_or(""));
+            let mut tempdir = TempDir::new(stem)?;
+            tempdir.keep = options.keep_tempdir;
+            util::copy_dir(&with_parent(original, Path::to_path_buf), &tempdir.path)?;
+            Some(tempdir)
+        } else {
+            None
+        };
+        let file = &match &tempdir {
+            Some(tempdir) => tempdir.path.join(name),
+            None => original.clone(),
+        };
+        // Where the intermediate files are written. Builds in a temporary directory are copied
+        // to the output directory afterwards, as are html builds, since make4ht doesn't support it
+        let job = &if tempdir.is_some() || format == OutFmt::Html {
+            file_options.output_dir = None;
+            file.clone()
+        } else if options.atomic_output {
+            // The engine never writes to the final output, so a viewer holding it open can't
+            // make the build fail
+            let dir = with_parent(&in_output_dir(&options, file), |p| p.join(ATOMIC_DIR));
+            std::fs::create_dir_all(&dir)?;
+            let dir = dir.canonicalize()?;
+            deps.output.insert(dir.clone());
+            file_options.output_dir = Some(dir.clone());
+            dir.join(name)
+        } else {
+            in_output_dir(&file_options, file)
+        };
+        let mut recipes = make_cmds(&file_options, providers);
+        let source_ext = dtx::source_ext(file);
+        if source_ext == Some("dtx") {
+            dtx::register(&mut recipes);
+        }
+        apply_run_limits(&file_options, &mut recipes);
+        select_recipes(&file_options, &mut recipes);
+        for (makes, recipe) in recipes.iter().filter(|(_, r)| r.uses != "tex") {
+            registered.insert(makes.clone(), recipe.program().to_string());
+        }
+        if let Some(ext) = source_ext {
+            // The engine runs on the source itself
+            for recipe in recipes.values_mut().filter(|r| r.uses == "tex") {
+                recipe.uses = ext;
+            }
+        }
+        if let Some(dir) = &file_options.output_dir {
+            // Recipes run next to their input in the output directory, so point them back at
+            // the sources
+            let source = with_parent(file, Path::canonicalize)?;
+            for var in ["TEXINPUTS", "BIBINPUTS", "BSTINPUTS"] {
+                let mut paths = vec![source.clone(), dir.clone()];
+                match std::env::var_os(var) {
+                    Some(existing) => paths.extend(std::env::split_paths(&existing)),
+                    // An empty entry stands for the default search path
+                    None => paths.push(PathBuf::new()),
+                }
+                let paths = std::env::join_paths(paths).map_err(|_| file_error("invalid path"))?;
+                for recipe in recipes.values_mut() {
+                    recipe.env.push((var, paths.to_string_lossy().into_owned()));
+                }
+            }
+        }
+        if options.fresh {
+            // Precious outputs are removed too, so the build really starts from scratch
+            let protected = protected(&options, base, &HashMap::new());
+            clean_previous(file, job, base, &recipes, &protected);
+        }
+        let (ran, passes) = if rmarkdown::is_rmarkdown(file) {
+            rmarkdown::render(&file_options, file, &mut deps)?;
+            (true, 1)
+        } else {
+            // Outputs of the files built before this one, which shouldn't be cleaned if it fails
+            let previous_outputs = deps.output.clone();
+            // Seed the dependencies, since the `.fls` only exists after the first run
+            let scanned = scan::scan_tex_deps(file);
+            deps.input.extend(scanned.iter().cloned());
+            // Reported right away, so watch mode still knows what to watch if the build fails
+            add_report_inputs(report, scanned.iter(), tempdir.as_ref(), original);
+            let batchmode = scan::sets_batchmode(file);
+            let log = job.with_extension("log");
+            let batch_log = Some(log.as_path()).filter(|_| batchmode);
+            let pass = |recipe: &Recipe, deps: &mut Deps| {
+                engine_pass(&options, recipe, file, base, batch_log, deps)
+            };
+            let mut recipe = engine_recipe(&recipes, base)?;
+            let max_passes = engine_passes(recipe, &options);
+            let previous_aux = aux_hash(job);
+            let mut rerun = false;
+            let mut passes = 1;
+            let mut runs = HashMap::new();
+            let mut failed = None;
+            let ran = options.force
+                || options.fresh
+                || !up_to_date(file, job, &scanned, &job.with_extension(base));
+            if ran {
+                // Until it succeeds, so a build that stops early isn't taken as up to date
+                buildlog::record_outcome(&job.with_extension(base), false)?;
+                if batchmode {
+                    message!(
+                        "{} {} sets \\batchmode, so the engine only writes its messages to {}",
+                        util::yellow("Warning:"),
+                        options.display_path(file),
+                        options.display_path(&log)
+                    );
+                }
+                // Before anything is written, so a missing engine fails right away
+                recipe.check_installed()?;
+                // The first pass is expected to fail on missing files, which are built next
+                let mut first = pass(recipe, &mut deps);
+                if first.is_err()
+                    && options.auto_engine
+                    && format == OutFmt::Pdf
+                    && file_options.engine.unwrap_or(latex::Engine::Pdflatex)
+                        == latex::Engine::Pdflatex
+                    && std::fs::read(&log).is_ok_and(|log| {
+                        latex::needs_unicode_engine(&String::from_utf8_lossy(&log))
+                    })
+                {
+                    message!(
+                        "{} needs a unicode engine, rebuilding it with xelatex",
+                        options.display_path(file)
+                    );
+                    file_options.engine = Some(latex::Engine::Xelatex);
+                    let switched = make_cmds(&file_options, providers)
+                        .remove(base)
+                        .ok_or_else(|| no_engine(base))?;
+                    if let Some(pdf) = recipes.get_mut(base) {
+                        pdf.script = switched.script;
+                        pdf.progress = switched.progress;
+                        pdf.requires = switched.requires;
+                    }
+                    recipe = engine_recipe(&recipes, base)?;
+                    first = pass(recipe, &mut deps);
+                }
+                if let Err(e) = first {
+                    if is_tool_not_found(&e) || e.kind() == std::io::ErrorKind::Interrupted {
+                        return Err(e);
+                    }
+                    // Kept until a later pass succeeds, since the error may just be a missing
+                    // file that's built next
+                    failed = Some(e);
+                }
+                rerun = needs_another_pass(job, previous_aux);
+                if !deps.packages.is_empty() && install_packages(&options, &deps.packages)? {
+                    rerun = true;
+                }
+            }
+            // The engine writes synctex data etc. to the output directory, not next to the source
+            recipe.collect_generated(job, &mut deps.output);
+            if format == OutFmt::Html {
+                // make4ht runs as many passes as it needs by itself
+                register_tex4ht_images(file, &mut deps.output);
+            } else {
+                match collect_files(&job.with_extension("fls"), &mut deps) {
+                    Err(e) if e.kind() == std::io::ErrorKind::InvalidData => message!(
+                        "{} ignoring {}: {}",
+                        util::yellow("Warning:"),
+                        options.display_path(&job.with_extension("fls")),
+                        e
+                    ),
+                    // Removed since the last build, which was up to date anyway
+                    Err(e) if !ran && e.kind() == std::io::ErrorKind::NotFound => (),
+                    result => result?,
+                }
+            }
+            check_output_size(&options, file, &deps)?;
+            if format != OutFmt::Html && !options.single_pass {
+                // Caches like `_minted-<stem>` are read back in, but never need building
+                let mut pending: Vec<PathBuf> = deps
+                    .input
+                    .iter()
+                    .filter(|d| !recipe.in_generated_dir(d))
+                    .cloned()
+                    .collect();
+                for dep in deps.missing.iter() {
+                    let dep = with_parent(job, |p| p.join(dep));
+                    if !pending.contains(&dep) {
+                        pending.push(dep);
+                    }
+                }
+                if build_deps(&pending, &mut deps, &recipes, options.max_passes, &mut runs)? {
+                    rerun = true;
+                }
+                let outputs: Vec<_> = deps.output.iter().cloned().collect();
+                if gnuplot::run_scripts(&file_options, &outputs, &mut deps)? {
+                    rerun = true;
+                }
+
+                // Cross references have settled once a pass leaves the `.aux` files unchanged
+                while rerun {
+                    if passes >= max_passes {
+                        message!(
+                            "{} {} didn't converge after {} passes",
+                            util::yellow("Warning:"),
+                            options.display_path(file),
+                            max_passes
+                        );
+                        break;
+                    }
+                    message!("Rerunning {}", latex::engine_name(&file_options));
+                    let before = aux_hash(job);
+                    if let Err(e) = pass(recipe, &mut deps) {
+                        failed = Some(e);
+                        break;
+                    }
+                    failed = None;
+                    passes += 1;
+                    rerun = needs_another_pass(job, before);
+                    // A pass can add citations, e.g. from a bibliography entry's crossref
+                    if build_deps(&pending, &mut deps, &recipes, options.max_passes, &mut runs)? {
+                        rerun = true;
+                    }
+                    check_output_size(&options, file, &deps)?;
+                }
+            }
+            if let Some(e) = failed {
+                if options.clean_on_failure {
+                    message!("Cleaning up after failed build of {}", name);
+                    let failed = deps.output.difference(&previous_outputs).cloned();
+                    clean(&failed.collect(), &protected(&options, base, &recipes));
+                    deps.output = previous_outputs;
+                }
+                if let Some(log) = &options.log_file {
+                    write_log(log, &deps.log)?;
+                }
+                return Err(e);
+            }
+            if ran {
+                buildlog::record_outcome(&job.with_extension(base), true)?;
+                mark_current(file, job, &scanned, &job.with_extension(base))?;
+            }
+            // Checked even if it was up to date, so --strict keeps failing until it's fixed
+            check_characters(&options, file, &job.with_extension("log"))?;
+            (ran, passes)
+        };
+        if ran && format != OutFmt::Html {
+            forget_removed(
+                &options,
+                original,
+                job,
+                &protected(&options, base, &recipes),
+            )?;
+        }
+        if options.dump_fls {
+            match parse_fls(&job.with_extension("fls")) {
+                Ok(fls) => {
+                    message!("Recorded files for {}:", options.display_path(original));
+                    fls.dump(&options);
+                }
+                Err(e) => message!(
+                    "Couldn't read .fls for {}: {}",
+                    options.display_path(original),
+                    e
+                ),
+            }
+        }
+        let output = final_output.clone();
+        if !ran && passes == 1 {
+            message!("{} is up to date", options.display_path(&output));
+        }
+        if job.with_extension(base) != output && job.with_extension(base).exists() {
+            util::replace_file(&job.with_extension(base), &output)
+                .map_err(|e| locked(&output, e))?;
+        }
+        // Only convert once the dvi has been built
+        let pdf = if options.dvi_to_pdf && output.exists() {
+            let mut convert = latex::dvi_to_pdf(&file_options);
+            // So images are found from the output directory, and timestamps stay reproducible
+            convert.env = engine_recipe(&recipes, base)?.env.clone();
+            let pdf = output.with_extension("pdf");
+            let before = util::modified(&pdf);
+            convert.run_for(&pdf, "pdf", &mut deps)?;
+            if util::modified(&pdf) != before {
+                message!("Converted to {}", options.display_path(&pdf));
+            }
+            Some(pdf)
+        } else {
+            None
+        };
+        if output.exists() {
+            if let Some(template) = &options.output_name {
+                let engine = latex::engine_name(&file_options);
+                let stem = original
+                    .file_stem()
+                    .map_or("", |s| s.to_str().unwrap_or(""));
+                let mut named = output.with_file_name(template.render(stem, engine));
+                if named.extension().is_none() {
+                    named.set_extension(base);
+                }
+                std::fs::copy(&output, &named)?;
+                message!(
+                    "Copied {} to {}",
+                    options.display_path(&output),
+                    options.display_path(&named)
+                );
+                report.outputs.push(named);
+            }
+            report.outputs.push(output);
+        }
+        report.outputs.extend(pdf);
+        if let Some(bib) = &options.export_bib {
+            let env = recipes.get("bbl").map_or(&[][..], |r| &r.env[..]);
+            export_bib(file, &job.with_extension("aux"), bib, env)?;
+            message!(
+                "Exported the cited entries to {}",
+                options.display_path(bib)
+            );
+            if !report.outputs.contains(bib) {
+                report.outputs.push(bib.clone());
+            }
+        }
+        if options.validate_pdfa {
+            let pdf = report
+                .outputs
+                .iter()
+                .rev()
+                .find(|p| p.extension() == Some("pdf".as_ref()));
+            match pdf {
+                Some(pdf) => {
+                    let failures = pdfa::validate(pdf)?;
+                    if !failures.is_empty() {
+                        let pdf = options.display_path(pdf);
+                        for failure in failures.iter() {
+                            report.diagnostics.push(format!("{}: {}", pdf, failure));
+                        }
+                        return Err(Error::other(format!("{} isn't PDF/A conformant", pdf)));
+                    }
+                    message!("{} is PDF/A conformant", options.display_path(pdf));
+                }
+                None => message!(
+                    "{} no pdf to check for {}",
+                    util::yellow("Warning:"),
+                    options.display_path(original)
+                ),
+            }
+        }
+        if let Some(hook) = &options.post_build {
+            if !run_hook(hook, original, &final_output)? {
+                if options.post_build_fatal {
+                    return Err(Error::other(format!(
+                        "--post-build command failed for {}",
+                        options.display_path(original)
+                    )));
+                }
+                message!(
+                    "{} --post-build command failed for {}",
+                    util::yellow("Warning:"),
+                    options.display_path(original)
+                );
+            }
+        }
+        let inputs = deps.input.iter().filter(|p| !deps.output.contains(*p));
+        add_report_inputs(report, inputs, tempdir.as_ref(), original);
+        for missing in deps.missing.iter() {
+            let missing = util::normalize(&match &tempdir {
+                Some(_) => with_parent(original, |p| p.join(missing)),
+                None => with_parent(job, |p| p.join(missing)),
+            });
+            if !missing.exists() && !report.missing.contains(&missing) {
+                report.missing.push(missing);
+            }
+        }
+        // Needed, even if what it makes was already up to date
+        let needed = deps.input.iter().map(PathBuf::as_path);
+        let needed = needed.chain(deps.missing.iter().map(Path::new));
+        for file in needed {
+            if let Some((makes, _, _)) = recipe_for(file, &recipes) {
+                used.insert(makes.to_string());
+            }
+        }
+        if let Some(tempdir) = &tempdir {
+            deps.output.retain(|p| !p.starts_with(&tempdir.path));
+        }
+        deps.clear();
+    }
+    if let Some(log) = &options.log_file {
+        write_log(log, &deps.log)?;
+    }
+    if options.print_log {
+        for file in options.files.iter() {
+            let log = in_output_dir(&options, file).with_extension("log");
+            match std::fs::read(&log) {
+                Ok(contents) => {
+                    let mut out = util::messages();
+                    writeln!(out, "==> {} <==", options.display_path(&log))?;
+                    out.write_all(&contents)?;
+                }
+                Err(_) => message!("No log found for {}", options.display_path(file)),
+            }
+        }
+    }
+    if options.verbose {
+        let unused: Vec<_> = registered
+            .iter()
+            .filter(|(makes, _)| !used.contains(*makes))
+            .map(|(makes, program)| format!("{} ({})", makes, program))
+            .collect();
+        if !unused.is_empty() {
+            message!("Recipes that weren't needed: {}", unused.join(", "));
+        }
+    }
+    if options.count {
+        print_counts(&options)?;
+    }
+    if options.list_packages {
+        for file in options.files.iter() {
+            let log = in_output_dir(&options, file).with_extension("log");
+            match std::fs::read(&log) {
+                Ok(contents) => {
+                    let mut out = util::messages();
+                    writeln!(out, "Packages loaded by {}:", options.display_path(file))?;
+                    for package in packages::loaded_packages(&String::from_utf8_lossy(&contents)) {
+                        writeln!(out, "  {}", package)?;
+                    }
+                }
+                Err(_) => message!("No log found for {}", options.display_path(file)),
+            }
+        }
+    }
+    if options.group_output {
+        let mut keep = vec![base, "synctex.gz"];
+        if options.dvi_to_pdf {
+            keep.push("pdf");
+        }
+        for dir in output_dirs(&options) {
+            group::sort(&dir, &mut deps.output, &keep, LOGS)?;
+        }
+    }
+    if options.view || options.goto_line.is_some() {
+        for file in options.files.iter() {
+            view::view(
+                &in_output_dir(&options, file).with_extension(base),
+                options.goto_line.as_ref(),
+            )?;
+        }
+    }
+    if options.clean {
+        message!("Cleaning up files");
+        for file in options.files.iter() {
+            let job = in_output_dir(&options, file);
+            let stem = job.file_stem().map_or("", |s| s.to_str().unwrap_or(""));
+            for ext in options.clean_ext.iter() {
+                let path = if ext.contains("%R") {
+                    job.with_file_name(ext.replace("%R", stem))
+                } else {
+                    job.with_extension(ext)
+                };
+                if path.exists() {
+                    deps.output.insert(path);
+                }
+            }
+        }
+        let recipes = make_cmds(&options, providers);
+        clean(&deps.output, &protected(&options, base, &recipes));
+        if options.group_output {
+            for dir in output_dirs(&options) {
+                group::remove_empty(&dir);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The output directories of the documents, each listed once
+fn output_dirs(options: &Options) -> Vec<PathBuf> {
+    let mut dirs = vec![];
+    for dir in options
+        .files
+        .iter()
+        .filter_map(|f| options.output_dir_for(f))
+    {
+        if !dirs.contains(&dir) {
+            dirs.push(dir);
+        }
+    }
+    dirs
+}
+
+/// Warn about the characters the fonts didn't have, which are left out of the output
+///
+/// With `--strict`, the build fails too
+fn check_characters(options: &Options, file: &Path, log: &Path) -> std::io::Result<()> {
+    let log = String::from_utf8_lossy(&std::fs::read(log).unwrap_or_default()).into_owned();
+    let missing = fonts::missing_characters(&log);
+    if missing.is_empty() {
+        return Ok(());
+    }
+    let mut out = util::messages();
+    writeln!(
+        out,
+        "{} {} uses characters its fonts don't have, which were left out:",
+        util::yellow("Warning:"),
+        options.display_path(file)
+    )?;
+    for (font, characters) in missing.iter() {
+        let characters: Vec<_> = characters.iter().map(String::as_str).collect();
+        writeln!(out, "  {}: {}", font, characters.join(", "))?;
+    }
+    if options.strict {
+        return Err(Error::other(format!(
+            "characters are missing from the output of {}",
+            options.display_path(file)
+        )));
+    }
+    Ok(())
+}
+
+/// Fail if the files written so far add up to more than `--max-output-size`
+///
+/// Checked after every pass, so a document writing in an endless loop can't fill the disk
+fn check_output_size(options: &Options, file: &Path, deps: &Deps) -> std::io::Result<()> {
+    let limit = match options.max_output_size {
+        Some(limit) => limit,
+        None => return Ok(()),
+    };
+    let size: u64 = deps
+        .output
+        .iter()
+        .filter_map(|output| output.metadata().ok())
+        .filter(|meta| meta.is_file())
+        .map(|meta| meta.len())
+        .sum();
+    if size > limit * 1024 * 1024 {
+        return Err(Error::other(format!(
+            "stopped building {}, the generated files add up to {:.1} MB, more than --max-output-size {}",
+            options.display_path(file),
+            size as f64 / (1024.0 * 1024.0),
+            limit
+        )));
+    }
+    Ok(())
+}
+
+/// Run the `--pre-build` or `--post-build` command `hook` for `file`, returning whether it
+/// succeeded
+fn run_hook(hook: &str, file: &Path, output: &Path) -> std::io::Result<bool> {
+    let stem = file.file_stem().map_or("", |s| s.to_str().unwrap_or(""));
+    let mut cmd = Command::new("bash");
+    cmd.arg("-c").arg(substitute(
+        hook,
+        &output.display().to_string(),
+        &file.display().to_string(),
+        stem,
+    ));
+    let output = util::output(cmd.stdout(util::messages_stdio()).stderr(Stdio::inherit()))?;
+    Ok(output.status.success())
+}
+
+/// Explain how to install the missing `packages`, or install them with `--auto-install`
+///
+/// Returns whether any were installed
+fn install_packages(options: &Options, packages: &HashSet<String>) -> std::io::Result<bool> {
+    let miktex = packages::is_miktex();
+    if options.auto_install && !miktex {
+        message!("Packages can only be installed automatically with MiKTeX");
+    }
+    let mut packages: Vec<_> = packages.iter().collect();
+    packages.sort();
+    let mut installed = false;
+    for package in packages {
+        if options.auto_install && miktex && packages::install(package)? {
+            installed = true;
+            continue;
+        }
+        message!(
+            "{} {} isn't installed, try `{}`",
+            util::yellow("Hint:"),
+            package,
+            packages::install_command(package, miktex)
+        );
+    }
+    Ok(installed)
+}
+
+/// A document source somewhere in `dir`
+fn find_source(dir: &Path) -> std::io::Result<Option<PathBuf>> {
+    for entry in dir.read_dir()? {
+        let entry = entry?;
+        // Links are removed, not followed
+        if entry.file_type()?.is_dir() {
+            if let Some(source) = find_source(&entry.path())? {
+                return Ok(Some(source));
+            }
+        } else if dtx::is_document(&entry.path()) {
+            return Ok(Some(entry.path()));
+        }
+    }
+    Ok(None)
+}
+
+/// Remove the output directories of the documents, for `--clean-output-dir`
+///
+/// A directory holding any sources isn't a dedicated build directory, so it's left alone
+fn clean_output_dirs(options: &Options) -> std::io::Result<()> {
+    let mut dirs: BTreeSet<PathBuf> = options
+        .files
+        .iter()
+        .filter_map(|file| options.output_dir_for(file))
+        .collect();
+    if options.output_dir_mode == OutputDirMode::Cwd {
+        dirs.extend(options.output_dir.clone());
+    }
+    for dir in dirs {
+        let canonical = match dir.canonicalize() {
+            Ok(dir) => dir,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        let source = match options
+            .files
+            .iter()
+            .find(|file| file.canonicalize().is_ok_and(|f| f.starts_with(&canonical)))
+        {
+            Some(file) => Some(file.clone()),
+            None => find_source(&canonical)?,
+        };
+        if let Some(source) = source {
+            return Err(Error::other(format!(
+                "not removing {}, since it contains {}",
+                options.display_path(&dir),
+                options.display_path(&source)
+            )));
+        }
+        message!("Removing {}", options.display_path(&dir));
+        std::fs::remove_dir_all(&canonical)?;
+    }
+    Ok(())
+}
+
+/// Print the programs building each file would likely run, for `--plan` and `--plan-json`
+fn print_plan(
+    options: &Options,
+    providers: &[Box<dyn RecipeProvider>],
+    shared: &DocumentConfig,
+) -> std::io::Result<()> {
+    let base = options.output_format().ext();
+    let mut plans = vec![];
+    for file in options.files.iter() {
+        let file_options = file_options(options, file, shared);
+        let mut recipes = make_cmds(&file_options, providers);
+        select_recipes(&file_options, &mut recipes);
+        let engine = engine_recipe(&recipes, base)?.program().to_string();
+        let job = in_output_dir(options, file);
+        let scanned = scan::scan_tex_deps(file);
+        let output = job.with_extension(base);
+        if !options.force && up_to_date(file, &job, &scanned, &output) {
+            if options.plan_json {
+                plans.push(serde_json::json!({
+                    "file": options.display_path(file),
+                    "up_to_date": true,
+                    "steps": [],
+                    "missing": [],
+                }));
+            } else {
+                message!("{}: up to date", options.display_path(file));
+            }
+            continue;
+        }
+        // The files the engine will probably ask for, which intermediate recipes make
+        let mut wanted = recorded_inputs(file, &job, &scanned);
+        if scanned
+            .iter()
+            .any(|d| d.extension().is_some_and(|e| e == "bib"))
+        {
+            wanted.push(job.with_extension("bbl"));
+        }
+        for package in scan::packages(file) {
+            match package.as_str() {
+                "sagetex" => wanted.push(job.with_extension("sagetex.sout")),
+                "glossaries" | "glossaries-extra" => wanted.push(job.with_extension("gls")),
+                _ => (),
+            }
+        }
+        let mut programs: Vec<String> = vec![];
+        let mut requires = engine_recipe(&recipes, base)?.requires.to_vec();
+        for dep in wanted.iter() {
+            if let Some((_, recipe, _)) = recipe_for(dep, &recipes) {
+                let program = recipe.program().to_string();
+                if program != engine && !programs.contains(&program) {
+                    programs.push(program);
+                    requires.extend(recipe.requires);
+                }
+            }
+        }
+        let mut steps = vec![engine.clone()];
+        if !programs.is_empty() && !options.single_pass {
+            steps.append(&mut programs);
+            // Once more for what they made, and again if the cross references change
+            steps.push(engine);
+        }
+        if options.dvi_to_pdf {
+            let convert = latex::dvi_to_pdf(&file_options);
+            steps.push(convert.program().to_string());
+            requires.extend(convert.requires);
+        }
+        let mut missing: Vec<_> = requires
+            .into_iter()
+            .filter(|tool| util::find_program(tool).is_none())
+            .collect();
+        missing.sort_unstable();
+        missing.dedup();
+        if options.plan_json {
+            plans.push(serde_json::json!({
+                "file": options.display_path(file),
+                "up_to_date": false,
+                "steps": steps,
+                "missing": missing,
+            }));
+            continue;
+        }
+        message!("{}: {}", options.display_path(file), steps.join(", "));
+        if !missing.is_empty() {
+            message!(
+                "  {} not installed: {}",
+                util::yellow("Warning:"),
+                missing.join(", ")
+            );
+        }
+    }
+    if options.plan_json {
+        println!("{}", serde_json::Value::from(plans));
+    }
+    Ok(())
+}
+
+/// Print the word count of each of the files
+fn print_counts(options: &Options) -> std::io::Result<()> {
+    for file in options.files.iter() {
+        let count = count::count(file)?;
+        message!(
+            "{}: {} words ({} in text, {} in headers, {} in captions), {} characters",
+            options.display_path(file),
+            count.words(),
+            count.text,
+            count.headers,
+            count.captions,
+            count.chars
+        );
+    }
+    Ok(())
+}
+
+/// Directory the intermediate files are written to with `--atomic-output`
+const ATOMIC_DIR: &str = ".latexmk-build";
+
+fn print_locked(output: impl std::fmt::Display) {
+    message!(
+        "{} {} is locked, close your viewer or use --atomic-output",
+        util::red("Error:"),
+        output
+    );
+}
+
+/// Explain a permission error writing `output`, which usually means a viewer has it open
+fn locked(output: &Path, e: Error) -> Error {
+    if e.kind() == std::io::ErrorKind::PermissionDenied {
+        print_locked(util::display_path(output));
+    }
+    e
+}
+
+/// Where `file` would be if it were in the output directory
+fn in_output_dir(options: &Options, file: &Path) -> PathBuf {
+    match (options.output_dir_for(file), file.file_name()) {
+        (Some(dir), Some(name)) => dir.join(name),
+        _ => file.to_path_buf(),
+    }
+}
+
+/// Extensions of the logs `--keep-logs` keeps
+const LOGS: &[&str] = &["log", "blg", "ilg", "glg"];
+
+/// Extensions of the files clean should never remove
+fn protected(options: &Options, final_ext: &str, recipes: &HashMap<String, Recipe>) -> Vec<String> {
+    let mut ret = vec![final_ext.to_string()];
+    if options.dvi_to_pdf {
+        ret.push("pdf".into());
+    }
+    if options.keep_logs {
+        ret.extend(LOGS.iter().map(|ext| ext.to_string()));
+    }
+    ret.extend(options.protect.iter().cloned());
+    for recipe in recipes.values() {
+        ret.extend(recipe.precious.iter().map(|ext| ext.to_string()));
+    }
+    ret
+}
+
+/// Remove generated files & directories, except those ending with a `protected` extension
+fn clean(files: &HashSet<PathBuf>, protected: &[String]) {
+    for file in files {
+        let name = file.file_name().map_or("", |s| s.to_str().unwrap_or(""));
+        if !protected
+            .iter()
+            .any(|ext| name.ends_with(&format!(".{}", ext)))
+            // The same file may be listed under another path, and already be removed
+            && file.exists()
+            && std::fs::remove_file(file).is_err()
+            && std::fs::remove_dir_all(file).is_err()
+        {
+            message!("Couldn't remove {}", util::display_path(file));
+        }
+    }
+}
+
+/// Compare the files recorded for the build of `file` at `job` with the previous build's
+///
+/// Outputs the engine no longer writes, like the `.aux` of a chapter that isn't included any
+/// more, would otherwise never be cleaned, so they're removed
+fn forget_removed(
+    options: &Options,
+    file: &Path,
+    job: &Path,
+    protected: &[String],
+) -> std::io::Result<()> {
+    let fls = job.with_extension("fls");
+    let current = match parse_fls(&fls) {
+        Ok(current) => buildlog::Recorded {
+            inputs: current.input,
+            outputs: current.output,
+        },
+        Err(_) => return Ok(()),
+    };
+    let previous = match buildlog::replace_recorded(&fls, current.clone())? {
+        Some(previous) => previous,
+        None => return Ok(()),
+    };
+    if options.verbose {
+        for input in previous.inputs.iter() {
+            if !current.inputs.contains(input) {
+                message!(
+                    "{} no longer reads {}",
+                    options.display_path(file),
+                    options.display_path(input)
+                );
+            }
+        }
+    }
+    let stale: HashSet<_> = previous
+        .outputs
+        .into_iter()
+        .filter(|output| !current.outputs.contains(output) && output.is_file())
+        .filter(|output| {
+            let name = output.file_name().map_or("", |s| s.to_str().unwrap_or(""));
+            !protected
+                .iter()
+                .any(|ext| name.ends_with(&format!(".{}", ext)))
+        })
+        .collect();
+    for output in stale.iter() {
+        message!(
+            "Removing {}, which {} no longer writes",
+            options.display_path(output),
+            options.display_path(file)
+        );
+    }
+    clean(&stale, protected);
+    Ok(())
+}
+
+/// Remove the files generated by the last build of `file`, with intermediates at `job`
+fn clean_previous(
+    file: &Path,
+    job: &Path,
+    base: &str,
+    recipes: &HashMap<String, Recipe>,
+    protected: &[String],
+) {
+    let mut previous = Deps::default();
+    let _ = collect_files(&job.with_extension("fls"), &mut previous);
+    // The engines for the other output formats don't run, so what they would generate is the
+    // user's, e.g. a `.dvi` next to a pdf build
+    let source = recipes.get(base).map(|r| r.uses);
+    let used = recipes
+        .iter()
+        .filter(|(makes, recipe)| *makes == base || Some(recipe.uses) != source)
+        .map(|(_, recipe)| recipe);
+    for recipe in used {
+        recipe.collect_generated(file, &mut previous.output);
+        recipe.collect_generated(job, &mut previous.output);
+    }
+    previous.output.insert(job.with_extension("fls"));
+    previous
+        .output
+        .retain(|p| p.exists() && !dtx::is_document(p));
+    message!("Cleaning up files from the previous build");
+    clean(&previous.output, protected);
+}
+
+fn file_error(e: &'static str) -> Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, e)
+}
+
+/// Contents of a `.fls` file, in the order they were recorded
+#[derive(Debug, Default)]
+pub struct Fls {
+    /// Directories the engine was run in
+    pub pwd: Vec<PathBuf>,
+    /// Files that were read from
+    pub input: Vec<PathBuf>,
+    /// Files that were written to
+    pub output: Vec<PathBuf>,
+}
+
+impl Fls {
+    /// Print the recorded paths, grouped by type
+    pub fn dump(&self, options: &Options) {
+        for (kind, paths) in [
+            ("PWD", &self.pwd),
+            ("INPUT", &self.input),
+            ("OUTPUT", &self.output),
+        ] {
+            message!("{} ({}):", kind, paths.len());
+            for path in paths {
+                message!("    {}", options.display_path(path));
+            }
+        }
+    }
+}
+
+/// Parse the `.fls` at `fls`, making paths absolute where possible
+pub fn parse_fls(fls: &Path) -> std::io::Result<Fls> {
+    let mut r = File::open(fls)?;
+    let mut s = String::new();
+    r.read_to_string(&mut s)?;
+    // TeX writes the directory first, and a whole line at a time, so anything else means it was
+    // stopped part way through
+    if !s.starts_with("PWD ") || !s.ends_with('\n') {
+        return Err(file_error(
+            "incomplete, the engine may have been interrupted",
+        ));
+    }
+    let mut ret = Fls::default();
+    // TeX starts with the directory it ran in, which is next to the `.fls` unless there's an
+    // output directory
+    let mut pwd = with_parent(fls, |p| p.canonicalize()).unwrap_or_else(|_| PathBuf::from("."));
+    for line in s.split('\n').filter(|s| s.trim() != "") {
+        let (cmd, file) = line
+            .trim()
+            .split_once(' ')
+            .ok_or(file_error("no space found"))?;
+        let mut path = PathBuf::from_str(file).map_err(|_| file_error("not a valid path"))?;
+        // make absolute if possible. Files outside the directory TeX ran in are recorded as
+        // e.g. `../shared/defs.tex`, so the `..` is resolved to match other spellings of the path
+        if !path.is_absolute() {
+            path = util::normalize(&pwd.join(path));
+        }
+        // Handle various possiblilities
+        if cmd == "PWD" {
+            pwd = path.clone();
+            ret.pwd.push(path);
+        } else if cmd == "INPUT" {
+            ret.input.push(path);
+        } else if cmd == "OUTPUT" {
+            ret.output.push(path);
+        } else {
+            return Err(file_error("unexpected line"));
+        }
+    }
+    Ok(ret)
+}
+
+fn collect_files(fls: &Path, deps: &mut Deps) -> std::io::Result<()> {
+    let fls = parse_fls(fls)?;
+    deps.input.extend(fls.input);
+    deps.output.extend(fls.output);
+    Ok(())
+}
+
+/// The recipe making `dep`, with the key it's registered under and the extension it matched
+fn recipe_for<'a>(
+    dep: &Path,
+    recipes: &'a HashMap<String, Recipe>,
+) -> Option<(&'a str, &'a Recipe, &'a str)> {
+    let name = dep.file_name()?.to_str()?;
+    recipes.iter().find_map(|(makes, recipe)| {
+        std::iter::once(makes.as_str())
+            .chain(recipe.produces.iter().copied())
+            .find(|ext| name.ends_with(ext))
+            .map(|ext| (makes.as_str(), recipe, ext))
+    })
+}
+
+/// Build each of `pending` that has a recipe, returning whether any were rebuilt
+///
+/// The recipes only depend on the latex output, not on each other, so they run in parallel.
+/// Outputs a recipe writes together are built by a single job, so it only runs once. `runs`
+/// counts how many times each recipe has run for the document so far.
+fn build_deps(
+    pending: &[PathBuf],
+    deps: &mut Deps,
+    recipes: &HashMap<String, Recipe>,
+    max_passes: usize,
+    runs: &mut HashMap<String, usize>,
+) -> std::io::Result<bool> {
+    // (key, recipe, input, outputs of it that are needed)
+    let mut groups: Vec<(&str, &Recipe, PathBuf, Vec<_>)> = vec![];
+    for dep in pending {
+        let (makes, recipe, ext) = match recipe_for(dep, recipes) {
+            Some(found) => found,
+            None => continue,
+        };
+        let input = dep.with_file_name(format!("{}.{}", output_stem(dep, ext), recipe.uses));
+        match groups
+            .iter_mut()
+            .find(|(_, r, i, _)| std::ptr::eq(*r, recipe) && *i == input)
+        {
+            Some((_, _, _, targets)) => targets.push((dep, ext)),
+            None => groups.push((makes, recipe, input, vec![(dep, ext)])),
+        }
+    }
+    // Each job collects its outputs separately, and they're merged once all have finished
+    let results: Vec<_> = std::thread::scope(|s| {
+        let jobs: Vec<_> = groups
+            .iter()
+            .map(|(makes, recipe, _, targets)| {
+                let ran = runs.get(*makes).copied().unwrap_or(0);
+                s.spawn(move || {
+                    let mut built = HashSet::new();
+                    let mut log = vec![];
+                    let result = build_dep(
+                        makes, recipe, targets, &mut built, &mut log, max_passes, ran,
+                    );
+                    (*makes, result, built, log)
+                })
+            })
+            .collect();
+        jobs.into_iter()
+            .map(|job| job.join().expect("Recipe panicked"))
+            .collect()
+    });
+    let mut rebuilt = false;
+    for (makes, result, built, log) in results {
+        // Every run is logged
+        *runs.entry(makes.to_string()).or_default() += log.len();
+        deps.output.extend(built);
+        deps.log.extend(log);
+        rebuilt |= result?;
+    }
+    Ok(rebuilt)
+}
+
+/// Set the `--max-runs` limits of `options` on the recipes they name
+fn apply_run_limits(options: &Options, recipes: &mut HashMap<String, Recipe>) {
+    for limit in options.max_runs.iter() {
+        match recipes.get_mut(&limit.recipe) {
+            Some(recipe) => recipe.max_runs = Some(limit.runs),
+            None => message!(
+                "{} --max-runs: there's no recipe making `{}`",
+                util::yellow("Warning:"),
+                limit.recipe
+            ),
+        }
+    }
+}
+
+/// Most times the `engine` may run, its own limit if it has one, otherwise `--max-passes`
+fn engine_passes(engine: &Recipe, options: &Options) -> usize {
+    engine.max_runs.unwrap_or(options.max_passes)
+}
+
+/// Warning that the recipe registered as `makes` won't run again for `name`, since it already
+/// ran `limit` times
+fn limit_reached(makes: &str, recipe: &Recipe, name: &str, limit: usize) -> String {
+    format!(
+        "{} {} ({}) reached its limit of {} runs, so {} may be out of date",
+        util::yellow("Warning:"),
+        makes,
+        recipe.program(),
+        limit,
+        name
+    )
+}
+
+/// Run `recipe`, registered as `makes`, if any of `targets` is out of date
+///
+/// `runs` is how many times it already ran for the document
+fn build_dep(
+    makes: &str,
+    recipe: &Recipe,
+    targets: &[(&PathBuf, &str)],
+    output: &mut HashSet<PathBuf>,
+    log: &mut Vec<(String, String)>,
+    max_passes: usize,
+    runs: usize,
+) -> std::io::Result<bool> {
+    for (dep, _) in targets {
+        output.insert(dep.to_path_buf());
+    }
+    let limit = recipe.max_runs.unwrap_or(usize::MAX);
+    for (dep, ext) in targets {
+        let name = dep.file_name().map_or("", |o| o.to_str().unwrap_or(""));
+        if runs >= limit {
+            if recipe.needs_run(dep, ext) {
+                message!("{}", limit_reached(makes, recipe, name, limit));
+            }
+            continue;
+        }
+        let mut ret = match recipe.on_file(dep, ext, output)? {
+            Some(ret) => ret,
+            None => continue,
+        };
+        let mut passes = 1;
+        while let Some(rerun) = recipe.rerun.filter(|_| ret.status.success()) {
+            if !rerun(dep, &ret) {
+                break;
+            }
+            if runs + passes >= limit {
+                message!("{}", limit_reached(makes, recipe, name, limit));
+                break;
+            }
+            if passes >= max_passes {
+                message!(
+                    "{} {} didn't converge after {} passes",
+                    util::yellow("Warning:"),
+                    name,
+                    max_passes
+                );
+                break;
+            }
+            message!("Rerunning {} on {}", recipe.program(), name);
+            log.push((util::display_path(dep), log_entry(&ret)));
+            ret = recipe.run(dep, ext, output)?;
+            passes += 1;
+        }
+        // The other outputs were written by the same run
+        for other in recipe.produces.iter() {
+            let path = dep.with_file_name(format!("{}.{}", output_stem(dep, ext), other));
+            if path.exists() {
+                output.insert(path);
+            }
+        }
+        log.push((util::display_path(dep), log_entry(&ret)));
+        recipe.check_found(&ret)?;
+        if ret.status.success() {
+            message!("Built {}", name);
+            return Ok(true);
+        } else {
+            // Hold the lock, so the output isn't interleaved with other recipes
+            let mut out = util::messages();
+            writeln!(out, "{} {}", util::red("Failed to build"), name)?;
+            out.write_all(&ret.stdout)?;
+            out.write_all(&ret.stderr)?;
+            return Ok(false);
+        }
+    }
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Clap;
+    use std::time::Duration;
+
+    /// Before the last build, which is when the inputs were written
+    fn before_build() -> SystemTime {
+        SystemTime::now() - Duration::from_secs(100)
+    }
+
+    /// Write `files` in `dir`, followed by the outputs of building `paper.tex`, which recorded
+    /// reading `recorded`
+    fn built(dir: &Path, files: &[(&str, &str)], recorded: &[&str]) -> PathBuf {
+        for (name, contents) in files {
+            std::fs::write(dir.join(name), contents).unwrap();
+            util::touch(&dir.join(name), before_build()).unwrap();
+        }
+        let mut fls = format!("PWD {}\n", dir.display());
+        for input in recorded {
+            fls.push_str(&format!("INPUT {}\n", input));
+        }
+        std::fs::write(dir.join("paper.fls"), fls).unwrap();
+        for ext in ["log", "pdf"] {
+            let output = dir.join("paper").with_extension(ext);
+            std::fs::write(&output, "").unwrap();
+            util::touch(&output, before_build() + Duration::from_secs(50)).unwrap();
+        }
+        dir.join("paper.tex")
+    }
+
+    /// Whether the `paper.pdf` built by [`built`] is up to date
+    fn pdf_up_to_date(tex: &Path) -> bool {
+        let scanned = scan::scan_tex_deps(tex);
+        up_to_date(tex, tex, &scanned, &tex.with_extension("pdf"))
+    }
+
+    /// Save `path` after the last build
+    fn edit(path: &Path) {
+        util::touch(path, SystemTime::now()).unwrap();
+    }
+
+    #[test]
+    fn script_with_spaces_and_quotes_in_names() {
+        let dir = TempDir::new("recipe-test").unwrap();
+        let recipe = Recipe::new("in", "cat %I > %O && echo %N >> %O");
+        for stem in ["My Thesis", "it's", "\"quoted\" $(id)"] {
+            std::fs::write(dir.path.join(format!("{}.in", stem)), "contents\n").unwrap();
+            let output = dir.path.join(format!("{}.out", stem));
+            let ran = recipe.run(&output, "out", &mut HashSet::new()).unwrap();
+            assert!(ran.status.success(), "{}", stem);
+            let written = std::fs::read_to_string(&output).unwrap();
+            assert_eq!(written, format!("contents\n{}\n", stem));
+        }
+    }
+
+    #[test]
+    fn script_runs_next_to_the_file() {
+        let dir = TempDir::new("recipe-test").unwrap();
+        let sub = dir.path.join("sub");
+        std::fs::create_dir(&sub).unwrap();
+        std::fs::write(sub.join("doc.in"), "").unwrap();
+        std::fs::write(sub.join("part.txt"), "sub\n").unwrap();
+        std::fs::write(dir.path.join("part.txt"), "top\n").unwrap();
+        // Whichever directory the build was started from
+        let output = sub.join("doc.out");
+        let recipe = Recipe::new("in", "cat part.txt > %O");
+        recipe.run(&output, "out", &mut HashSet::new()).unwrap();
+        assert_eq!(std::fs::read_to_string(&output).unwrap(), "sub\n");
+        let recipe = Recipe {
+            work_dir: Some(dir.path.clone()),
+            ..Recipe::new("in", "cat part.txt > sub/doc.out")
+        };
+        recipe.run(&output, "out", &mut HashSet::new()).unwrap();
+        assert_eq!(std::fs::read_to_string(&output).unwrap(), "top\n");
+    }
+
+    #[test]
+    fn prompt_gets_end_of_file() {
+        let dir = TempDir::new("recipe-test").unwrap();
+        std::fs::write(dir.path.join("doc.in"), "").unwrap();
+        let output = dir.path.join("doc.out");
+        // Like TeX asking for a file it couldn't find, which would wait forever for an answer
+        let recipe = Recipe::new("in", "read -r answer || { echo eof > %O; exit 1; }");
+        let ret = recipe.run(&output, "out", &mut HashSet::new()).unwrap();
+        assert!(!ret.status.success());
+        assert_eq!(std::fs::read_to_string(&output).unwrap(), "eof\n");
+    }
+
+    #[test]
+    fn recipe_asks_for_rerun() {
+        let dir = TempDir::new("recipe-test").unwrap();
+        std::fs::write(dir.path.join("paper.bcf"), "").unwrap();
+        let bbl = dir.path.join("paper.bbl");
+        // Like biber when the entries cross reference each other, the first run isn't enough
+        let recipe = Recipe {
+            rerun: Some(|_, output| String::from_utf8_lossy(&output.stdout).contains("rerun")),
+            ..Recipe::new(
+                "bcf",
+                "if [ -e %O ]; then echo second >> %O; else echo first > %O; echo rerun; fi",
+            )
+        };
+        let build = |max_passes| {
+            let _ = std::fs::remove_file(&bbl);
+            let mut log = vec![];
+            let targets = [(&bbl, "bbl")];
+            build_dep(
+                "bbl",
+                &recipe,
+                &targets,
+                &mut HashSet::new(),
+                &mut log,
+                max_passes,
+                0,
+            )
+            .unwrap();
+            (std::fs::read_to_string(&bbl).unwrap(), log.len())
+        };
+        assert_eq!(build(5), ("first\nsecond\n".into(), 2));
+        // Capped by --max-passes
+        assert_eq!(build(1), ("first\n".into(), 1));
+    }
+
+    #[test]
+    fn recipe_at_its_limit_is_skipped() {
+        let dir = TempDir::new("recipe-test").unwrap();
+        std::fs::write(dir.path.join("paper.aux"), "").unwrap();
+        let bbl = dir.path.join("paper.bbl");
+        let recipe = Recipe {
+            max_runs: Some(1),
+            ..Recipe::new("aux", "bibtex %N; echo run >> %O")
+        };
+        let build = |runs| {
+            let targets = [(&bbl, "bbl")];
+            let mut log = vec![];
+            build_dep(
+                "bbl",
+                &recipe,
+                &targets,
+                &mut HashSet::new(),
+                &mut log,
+                5,
+                runs,
+            )
+            .unwrap()
+        };
+        // It already ran once for the document, so even though the .bbl is missing
+        assert!(!build(1));
+        assert!(!bbl.exists());
+        assert!(recipe.needs_run(&bbl, "bbl"));
+        assert_eq!(
+            limit_reached("bbl", &recipe, "paper.bbl", 1),
+            "Warning: bbl (bibtex) reached its limit of 1 runs, so paper.bbl may be out of date"
+        );
+        assert!(build(0));
+        assert_eq!(std::fs::read_to_string(&bbl).unwrap(), "run\n");
+    }
+
+    #[test]
+    fn engine_limit_overrides_max_passes() {
+        let passes = |args: &[&str]| {
+            let options = Options::try_parse_from(["latexmk"].iter().chain(args)).unwrap();
+            let mut recipes = make_cmds(&options, &builtin_providers());
+            apply_run_limits(&options, &mut recipes);
+            engine_passes(engine_recipe(&recipes, "pdf").unwrap(), &options)
+        };
+        assert_eq!(passes(&["--max-passes", "3"]), 3);
+        assert_eq!(passes(&["--max-passes", "3", "--max-runs", "pdf=7"]), 7);
+        assert_eq!(passes(&["--max-runs", ".pdf=1", "--max-runs", "bbl=2"]), 1);
+    }
+
+    #[test]
+    fn output_over_the_size_limit() {
+        let dir = TempDir::new("recipe-test").unwrap();
+        let tex = dir.path.join("paper.tex");
+        std::fs::write(dir.path.join("paper.in"), "").unwrap();
+        let recipe = Recipe::new("in", "head -c 1500000 /dev/zero > %O");
+        let mut deps = Deps::default();
+        let out = dir.path.join("paper.out");
+        recipe.run(&out, "out", &mut deps.output).unwrap();
+        deps.output.insert(out);
+        let options = |limit| Options::try_parse_from(["latexmk", "--max-output-size", limit]);
+        assert!(check_output_size(&options("2").unwrap(), &tex, &deps).is_ok());
+        let e = check_output_size(&options("1").unwrap(), &tex, &deps).unwrap_err();
+        assert!(e.to_string().contains("1.4 MB"), "{}", e);
+        // No limit by default
+        let options = Options::try_parse_from(["latexmk"]).unwrap();
+        assert!(check_output_size(&options, &tex, &deps).is_ok());
+    }
+
+    #[test]
+    fn fls_paths_outside_pwd() {
+        let dir = TempDir::new("recipe-test").unwrap();
+        let sub = dir.path.join("sub");
+        std::fs::create_dir(&sub).unwrap();
+        let fls = sub.join("doc.fls");
+        std::fs::write(
+            &fls,
+            format!(
+                "PWD {}\nINPUT doc.tex\nINPUT ../shared/defs.tex\nINPUT ./fig.png\nOUTPUT doc.aux\n",
+                sub.display()
+            ),
+        )
+        .unwrap();
+        let fls = parse_fls(&fls).unwrap();
+        assert_eq!(
+            fls.input,
+            vec![
+                sub.join("doc.tex"),
+                dir.path.join("shared/defs.tex"),
+                sub.join("fig.png")
+            ]
+        );
+        assert_eq!(fls.output, vec![sub.join("doc.aux")]);
+    }
+
+    #[test]
+    fn truncated_fls() {
+        let dir = TempDir::new("recipe-test").unwrap();
+        let fls = dir.path.join("doc.fls");
+        let pwd = format!("PWD {}\n", dir.path.display());
+        // Stopped part way through a line, or before the directory was written
+        for contents in [format!("{}INPUT doc.t", pwd), String::new()] {
+            std::fs::write(&fls, contents).unwrap();
+            let e = parse_fls(&fls).unwrap_err();
+            assert!(e.to_string().contains("incomplete"), "{}", e);
+        }
+        std::fs::write(&fls, format!("{}INPUT doc.tex\n", pwd)).unwrap();
+        assert_eq!(
+            parse_fls(&fls).unwrap().input,
+            vec![dir.path.join("doc.tex")]
+        );
+    }
+
+    #[test]
+    fn pgfplots_tables_are_inputs() {
+        let dir = TempDir::new("recipe-test").unwrap();
+        let tex = built(
+            &dir.path,
+            &[
+                (
+                    "paper.tex",
+                    "\\addplot table {data.dat};\n\\pgfplotstableread{more.dat}\\more\n",
+                ),
+                ("data.dat", ""),
+                ("more.dat", ""),
+            ],
+            &["paper.tex"],
+        );
+        assert!(pdf_up_to_date(&tex));
+        for table in ["data.dat", "more.dat"] {
+            edit(&dir.path.join(table));
+            assert!(!pdf_up_to_date(&tex), "{}", table);
+            util::touch(&dir.path.join(table), before_build()).unwrap();
+        }
+        // A table that was removed isn't needed any more
+        std::fs::remove_file(dir.path.join("data.dat")).unwrap();
+        let scanned = scan::scan_tex_deps(&tex);
+        assert!(!recorded_inputs(&tex, &tex, &scanned).contains(&dir.path.join("data.dat")));
+        assert!(pdf_up_to_date(&tex));
+    }
+
+    #[test]
+    fn bibliography_sources_are_inputs() {
+        let dir = TempDir::new("recipe-test").unwrap();
+        let options = Options::try_parse_from(["latexmk"]).unwrap();
+        let mut recipes = HashMap::new();
+        Bibtex.register(&options, &mut recipes);
+        let bibtex = &recipes["bbl"];
+        let aux = "\\citation{knuth}\n\\bibdata{refs}\n\\bibstyle{mystyle}\n";
+        built(
+            &dir.path,
+            &[("paper.aux", aux), ("refs.bib", ""), ("mystyle.bst", "")],
+            &[],
+        );
+        let bbl = dir.path.join("paper.bbl");
+        std::fs::write(&bbl, "").unwrap();
+        util::touch(&bbl, before_build() + Duration::from_secs(50)).unwrap();
+        assert_eq!(
+            bibtex_deps(&dir.path.join("paper.aux")),
+            vec![dir.path.join("refs.bib"), dir.path.join("mystyle.bst")]
+        );
+        assert!(!bibtex.needs_run(&bbl, "bbl"));
+        for source in ["refs.bib", "mystyle.bst"] {
+            edit(&dir.path.join(source));
+            assert!(bibtex.needs_run(&bbl, "bbl"), "{}", source);
+            util::touch(&dir.path.join(source), before_build()).unwrap();
+        }
+        // Once the document uses another style, the old one doesn't matter
+        built(
+            &dir.path,
+            &[("paper.aux", "\\citation{knuth}\n\\bibdata{refs}\n")],
+            &[],
+        );
+        edit(&dir.path.join("mystyle.bst"));
+        assert!(!bibtex.needs_run(&bbl, "bbl"));
+    }
+
+    #[test]
+    fn chapter_aux_files_are_tracked() {
+        let dir = TempDir::new("recipe-test").unwrap();
+        let d = &dir.path;
+        let tex = built(
+            d,
+            &[
+                ("paper.tex", ""),
+                ("paper.aux", "\\@input{chap1.aux}\n\\@input{chap2.aux}\n"),
+                ("chap1.aux", ""),
+                ("chap2.aux", ""),
+            ],
+            &[],
+        );
+        let fls = |chapters: &[&str]| {
+            let mut fls = format!("PWD {}\nINPUT paper.tex\nOUTPUT paper.aux\n", d.display());
+            for chapter in chapters {
+                fls.push_str(&format!("INPUT {0}.aux\nOUTPUT {0}.aux\n", chapter));
+            }
+            std::fs::write(d.join("paper.fls"), fls).unwrap();
+        };
+        fls(&["chap1", "chap2"]);
+        let mut deps = Deps::default();
+        collect_files(&d.join("paper.fls"), &mut deps).unwrap();
+        for aux in ["paper.aux", "chap1.aux", "chap2.aux"] {
+            assert!(deps.output.contains(&d.join(aux)), "{}", aux);
+        }
+        // The engine writes them itself, so a change asks for another pass instead of a build
+        assert!(!recorded_inputs(&tex, &tex, &[]).contains(&d.join("chap1.aux")));
+        let before = aux_hash(&tex);
+        std::fs::write(d.join("chap2.aux"), "\\newlabel{x}{{1}{1}}\n").unwrap();
+        assert_ne!(aux_hash(&tex), before);
+        // Not once the chapter isn't included any more
+        fls(&["chap1"]);
+        let before = aux_hash(&tex);
+        std::fs::write(d.join("chap2.aux"), "").unwrap();
+        assert_eq!(aux_hash(&tex), before);
+    }
+
+    #[test]
+    fn removed_inputs_are_forgotten() {
+        let dir = TempDir::new("recipe-test").unwrap();
+        let d = &dir.path;
+        let options = Options::try_parse_from(["latexmk"]).unwrap();
+        let tex = built(
+            d,
+            &[("paper.tex", ""), ("figure.pdf", ""), ("chap2.aux", "")],
+            &[],
+        );
+        let fls = |lines: &str| {
+            std::fs::write(
+                d.join("paper.fls"),
+                format!("PWD {}\n{}", d.display(), lines),
+            )
+            .unwrap();
+        };
+        fls("INPUT paper.tex\nINPUT figure.pdf\nOUTPUT chap2.aux\n");
+        assert!(pdf_up_to_date(&tex));
+        edit(&d.join("figure.pdf"));
+        assert!(!pdf_up_to_date(&tex));
+        util::touch(&d.join("figure.pdf"), before_build()).unwrap();
+        forget_removed(&options, &tex, &tex, &[]).unwrap();
+        // The next build stopped reading the figure and writing the chapter
+        fls("INPUT paper.tex\n");
+        forget_removed(&options, &tex, &tex, &[]).unwrap();
+        assert!(!d.join("chap2.aux").exists());
+        assert!(d.join("figure.pdf").exists());
+        let recorded = &buildlog::BuildLog::load(&tex).recorded["paper.fls"];
+        assert_eq!(recorded.inputs, vec![d.join("paper.tex")]);
+        edit(&d.join("figure.pdf"));
+        assert!(pdf_up_to_date(&tex));
+    }
+
+    #[test]
+    fn passes_until_aux_settles() {
+        let dir = TempDir::new("recipe-test").unwrap();
+        let job = dir.path.join("paper.tex");
+        std::fs::write(
+            job.with_extension("log"),
+            "LaTeX Warning: Label(s) may have changed.",
+        )
+        .unwrap();
+        // The first pass of a new document
+        assert!(needs_another_pass(&job, None));
+        std::fs::write(job.with_extension("log"), "").unwrap();
+        assert!(!needs_another_pass(&job, None));
+        std::fs::write(job.with_extension("aux"), "\\newlabel{x}{{1}{1}}\n").unwrap();
+        let before = aux_hash(&job);
+        std::fs::write(job.with_extension("aux"), "\\newlabel{x}{{2}{1}}\n").unwrap();
+        assert!(needs_another_pass(&job, before));
+        // Settled, so no more passes, whatever the log says
+        let before = aux_hash(&job);
+        std::fs::write(
+            job.with_extension("log"),
+            "Rerun to get cross-references right.",
+        )
+        .unwrap();
+        assert!(!needs_another_pass(&job, before));
+    }
+
+    /// The recipes of the latex engines, run with `args`
+    fn latex_recipes(args: &[&str]) -> HashMap<String, Recipe> {
+        let options = Options::try_parse_from(["latexmk"].iter().chain(args)).unwrap();
+        let mut recipes = HashMap::new();
+        latex::Latex.register(&options, &mut recipes);
+        recipes
+    }
+
+    #[test]
+    fn minted_cache_is_output() {
+        let dir = TempDir::new("recipe-test").unwrap();
+        let d = &dir.path;
+        std::fs::create_dir(d.join("_minted-paper")).unwrap();
+        let tex = built(
+            d,
+            &[("paper.tex", ""), ("_minted-paper/5F3A.pygtex", "")],
+            &[],
+        );
+        std::fs::write(
+            d.join("paper.fls"),
+            format!(
+                "PWD {}\nINPUT paper.tex\nOUTPUT _minted-paper/5F3A.pygtex\n\
+                 INPUT _minted-paper/5F3A.pygtex\n",
+                d.display()
+            ),
+        )
+        .unwrap();
+        let cached = d.join("_minted-paper/5F3A.pygtex");
+        let mut deps = Deps::default();
+        collect_files(&d.join("paper.fls"), &mut deps).unwrap();
+        assert!(deps.output.contains(&cached));
+        assert!(!recorded_inputs(&tex, &tex, &[]).contains(&cached));
+        // So it's never built, and clean removes the whole directory
+        let engine = &latex_recipes(&[])["pdf"];
+        assert!(engine.in_generated_dir(&cached));
+        assert!(engine.in_generated_dir(&d.join("_minted/5F3A.pygtex")));
+        assert!(!engine.in_generated_dir(&d.join("figures/plot.pdf")));
+        let mut generated = HashSet::new();
+        engine.collect_generated(&tex, &mut generated);
+        assert!(generated.contains(&d.join("_minted-paper")));
+    }
+
+    #[test]
+    fn synctex_in_output_dir() {
+        let dir = TempDir::new("recipe-test").unwrap();
+        let d = &dir.path;
+        let build = d.join("build");
+        std::fs::create_dir(&build).unwrap();
+        let tex = built(d, &[("paper.tex", ""), ("build/paper.synctex.gz", "")], &[]);
+        // The engine runs next to the source, and writes to the output directory
+        std::fs::write(
+            build.join("paper.fls"),
+            format!(
+                "PWD {}\nINPUT paper.tex\nOUTPUT build/paper.log\nOUTPUT build/paper.synctex.gz\n",
+                d.display()
+            ),
+        )
+        .unwrap();
+        let job = build.join("paper.tex");
+        let synctex = build.join("paper.synctex.gz");
+        let mut deps = Deps::default();
+        collect_files(&job.with_extension("fls"), &mut deps).unwrap();
+        assert!(deps.output.contains(&synctex));
+        assert!(!deps.input.contains(&synctex));
+        let engine = &latex_recipes(&["-o", "build"])["pdf"];
+        let mut generated = HashSet::new();
+        engine.collect_generated(&job, &mut generated);
+        assert!(generated.contains(&synctex));
+        assert!(!generated.contains(&tex.with_extension("synctex.gz")));
+    }
+
+    #[test]
+    fn bibtex_args() {
+        let options = Options::try_parse_from([
+            "latexmk",
+            "--bibtex-args",
+            "-min-crossrefs=5",
+            "--bibtex-args",
+            "two words",
+            "--bibtex-args=100%N",
+        ])
+        .unwrap();
+        let mut recipes = HashMap::new();
+        Bibtex.register(&options, &mut recipes);
+        assert_eq!(
+            recipes["bbl"].script,
+            "bibtex -min-crossrefs=5 'two words' '100%%N' %N"
+        );
+    }
+
+    #[test]
+    fn literal_percent_before_placeholder() {
+        assert_eq!(
+            substitute("cp %I %O # 100%%N %%%N %x%", "my out.pdf", "%N.tex", "p"),
+            "cp '%N.tex' 'my out.pdf' # 100%N %p %x%"
+        );
+        let recipes = latex_recipes(&["--draft-banner", "50%Info"]);
+        let script = substitute(&recipes["pdf"].script, "paper.pdf", "paper.tex", "paper");
+        assert!(script.contains("{50\\%Info}"), "{}", script);
+    }
+
+    #[test]
+    fn failed_bibtex_leaves_no_bbl() {
+        let dir = TempDir::new("recipe-test").unwrap();
+        std::fs::write(dir.path.join("paper.aux"), "").unwrap();
+        let bbl = dir.path.join("paper.bbl");
+        let options = Options::try_parse_from(["latexmk"]).unwrap();
+        let mut recipes = HashMap::new();
+        Bibtex.register(&options, &mut recipes);
+        // Bibtex names the .bbl after the job itself, rather than writing to %O
+        let mut recipe = recipes.remove("bbl").unwrap();
+        recipe.script = "echo '\\begin{thebibliography}' > %N.bbl; exit 2".into();
+        let ret = recipe.run(&bbl, "bbl", &mut HashSet::new()).unwrap();
+        assert!(!ret.status.success());
+        assert!(!bbl.exists());
+        assert!(!partial_path(&bbl).exists());
+        // Written to the temporary name, so the last good one is kept
+        std::fs::write(&bbl, "good").unwrap();
+        recipe.script = "echo half > %O; exit 2".into();
+        recipe.run(&bbl, "bbl", &mut HashSet::new()).unwrap();
+        assert_eq!(std::fs::read_to_string(&bbl).unwrap(), "good");
+        assert!(!partial_path(&bbl).exists());
+        recipe.script = "echo new > %O".into();
+        recipe.run(&bbl, "bbl", &mut HashSet::new()).unwrap();
+        assert_eq!(std::fs::read_to_string(&bbl).unwrap(), "new\n");
+        assert!(!partial_path(&bbl).exists());
+    }
+
+    #[test]
+    fn missing_file_notes() {
+        let log = "No file paper.aux.\nNo file paper.toc.\r\nNo file .\nNo file ";
+        let expected: HashSet<String> = ["paper.aux", "paper.toc"].map(String::from).into();
+        assert_eq!(find(log), expected);
+    }
+
+    #[test]
+    fn batchmode_notes_from_log() {
+        let dir = TempDir::new("recipe-test").unwrap();
+        let tex = dir.path.join("paper.tex");
+        std::fs::write(&tex, "\\batchmode\n").unwrap();
+        let log = dir.path.join("paper.log");
+        let notes = "No file paper.bbl.\n! LaTeX Error: File `foo.sty' not found.\n";
+        std::fs::write(dir.path.join("notes.txt"), notes).unwrap();
+        // Like the engine in batchmode, which prints nothing
+        let recipe = Recipe::new("tex", "cat notes.txt > %N.log");
+        let options = Options::try_parse_from(["latexmk"]).unwrap();
+        let mut deps = Deps::default();
+        engine_pass(&options, &recipe, &tex, "pdf", Some(&log), &mut deps).unwrap();
+        assert_eq!(deps.missing, ["paper.bbl".to_string()].into());
+        assert_eq!(deps.packages, ["foo".to_string()].into());
+        // Otherwise only what was printed is read
+        let mut deps = Deps::default();
+        engine_pass(&options, &recipe, &tex, "pdf", None, &mut deps).unwrap();
+        assert!(deps.missing.is_empty());
+    }
+
+    /// The recipes left after selecting with `args`
+    fn selected(args: &[&str]) -> Vec<String> {
+        let options = Options::try_parse_from(["latexmk"].iter().chain(args)).unwrap();
+        let mut recipes: HashMap<String, Recipe> = [
+            ("pdf", "tex"),
+            ("bbl", "aux"),
+            ("gls", "glo"),
+            ("idx.html", "bbl"),
+        ]
+        .iter()
+        .map(|(makes, uses)| (makes.to_string(), Recipe::new(uses, "true")))
+        .collect();
+        select_recipes(&options, &mut recipes);
+        let mut kept: Vec<_> = recipes.into_keys().collect();
+        kept.sort();
+        kept
+    }
+
+    #[test]
+    fn external_documents_go_first() {
+        let dir = TempDir::new("recipe-test").unwrap();
+        let d = &dir.path;
+        let main = d.join("main.tex");
+        let other = d.join("other.tex");
+        let appendix = d.join("appendix.tex");
+        std::fs::write(
+            &main,
+            "\\externaldocument{other}\n\\externaldocument{gone}\n",
+        )
+        .unwrap();
+        std::fs::write(&other, "\\externaldocument{appendix}\n").unwrap();
+        // Referencing each other back isn't a cycle to follow forever
+        std::fs::write(&appendix, "\\externaldocument{other}\n").unwrap();
+        let order = |given: &[&PathBuf]| {
+            let mut files = vec![];
+            for file in given {
+                with_external_documents(file, &mut files, &mut vec![]);
+            }
+            files
+        };
+        // The documents it reads labels from are added, even though only one was given, and there's
+        // no `gone.tex` to build
+        assert_eq!(
+            order(&[&main]),
+            vec![appendix.clone(), other.clone(), main.clone()]
+        );
+        // Given in the wrong order, or more than once, each is still built once and before
+        assert_eq!(
+            order(&[&main, &other, &main]),
+            vec![appendix.clone(), other.clone(), main.clone()]
+        );
+        assert_eq!(order(&[&appendix]), vec![other, appendix]);
+    }
+
+    #[test]
+    fn config_precedence() {
+        let dir = TempDir::new("recipe-test").unwrap();
+        let tex = dir.path.join("paper.tex");
+        std::fs::write(&tex, "% !TEX program = lualatex\n").unwrap();
+        let project = dir.path.join(config::PROJECT_FILE);
+        std::fs::write(&project, "engine = \"xelatex\"\nlatex-args = [\"-8bit\"]\n").unwrap();
+        let global = dir.path.join("config.toml");
+        std::fs::write(
+            &global,
+            "shell-escape = true\nlatex-args = [\"-draftmode\"]\n",
+        )
+        .unwrap();
+        let mut shared = DocumentConfig::read(&project).unwrap().unwrap();
+        shared.merge(&DocumentConfig::read(&global).unwrap().unwrap());
+        let options = |args: &[&str]| {
+            let options = Options::try_parse_from(["latexmk"].iter().chain(args)).unwrap();
+            file_options(&options, &tex, &shared)
+        };
+        // The magic comment is the document's own setting, so it wins over the project's
+        let defaults = options(&[]);
+        assert_eq!(defaults.engine, Some(latex::Engine::Lualatex));
+        assert!(defaults.shell_escape);
+        assert_eq!(defaults.latex_args, vec!["-8bit"]);
+        std::fs::write(
+            config::path_for(&tex),
+            "engine = \"pdflatex\"\nshell-escape = false\n",
+        )
+        .unwrap();
+        let document = options(&[]);
+        assert_eq!(document.engine, Some(latex::Engine::Pdflatex));
+        assert!(!document.shell_escape);
+        assert_eq!(document.latex_args, vec!["-8bit"]);
+        let cli = options(&["--engine", "xelatex", "--latex-args=-halt-on-error"]);
+        assert_eq!(cli.engine, Some(latex::Engine::Xelatex));
+        assert_eq!(cli.latex_args, vec!["-halt-on-error"]);
+    }
+
+    #[test]
+    fn select_enabled_recipes() {
+        assert_eq!(selected(&[]), ["bbl", "gls", "idx.html", "pdf"]);
+        // The engine is kept, even when it isn't enabled
+        assert_eq!(selected(&["--enable-recipe", "gls"]), ["gls", "pdf"]);
+        assert_eq!(
+            selected(&["--enable-recipe", "gls", "--enable-recipe", "bbl"]),
+            ["bbl", "gls", "pdf"]
+        );
+        assert_eq!(selected(&["--enable-recipe", "nope"]), ["pdf"]);
+    }
+
+    #[test]
+    fn select_disabled_recipes() {
+        assert_eq!(
+            selected(&["--disable-recipe", "bbl"]),
+            ["gls", "idx.html", "pdf"]
+        );
+        assert_eq!(
+            selected(&["--disable-recipe", "pdf", "--disable-recipe", "gls"]),
+            ["bbl", "idx.html", "pdf"]
+        );
+        // Disabling wins over enabling
+        assert_eq!(
+            selected(&["--enable-recipe", "bbl", "--disable-recipe", "bbl"]),
+            ["pdf"]
+        );
+    }
+
+    #[test]
+    fn clean_output_dir_keeps_sources() {
+        let dir = TempDir::new("recipe-test").unwrap();
+        let src = dir.path.join("src");
+        let build = dir.path.join("build");
+        std::fs::create_dir_all(src.join("chapters")).unwrap();
+        std::fs::create_dir_all(build.join("chapters")).unwrap();
+        let paper = src.join("paper.tex");
+        std::fs::write(&paper, "").unwrap();
+        std::fs::write(build.join("paper.aux"), "").unwrap();
+        let clean = |output_dir: &Path, mode: &str| {
+            let options = Options::try_parse_from([
+                "latexmk".as_ref(),
+                "--clean-output-dir".as_ref(),
+                "--output-dir-mode".as_ref(),
+                mode.as_ref(),
+                "-o".as_ref(),
+                output_dir.as_os_str(),
+                paper.as_os_str(),
+            ])
+            .unwrap();
+            clean_output_dirs(&options)
+        };
+        // The document itself, or any other source
+        assert!(clean(&src, "cwd").is_err());
+        assert!(clean(Path::new("."), "source").is_err());
+        std::fs::write(build.join("chapters/intro.tex"), "").unwrap();
+        assert!(clean(&build, "cwd").is_err());
+        assert!(build.join("paper.aux").exists());
+        std::fs::remove_file(build.join("chapters/intro.tex")).unwrap();
+        clean(&build, "cwd").unwrap();
+        assert!(!build.exists());
+        assert!(paper.exists());
+    }
 }
@@ -0,0 +1,79 @@
+//
+// rmarkdown.rs
+// Copyright (C) 2021 matthew <matthew@matthew-ubuntu>
+// Distributed under terms of the MIT license.
+//
+
+//! R markdown documents
+//!
+//! These are rendered by R's rmarkdown package, which runs knitr, pandoc and latex itself, so
+//! they take a separate route instead of going through the latex recipes. This requires R with
+//! rmarkdown installed.
+
+use std::path::Path;
+
+use crate::{
+    recipe::{Deps, Recipe},
+    util::shell_quote,
+    Options, OutFmt,
+};
+
+/// Whether `file` should be rendered with rmarkdown instead of latex
+pub fn is_rmarkdown(file: &Path) -> bool {
+    file.extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("rmd"))
+}
+
+/// Recipe rendering an R markdown document in the selected output format
+fn recipe(options: &Options) -> std::io::Result<Recipe> {
+    let format = match options.output_format() {
+        OutFmt::Pdf => "pdf_document",
+        OutFmt::Html => "html_document",
+        OutFmt::Dvi => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "R markdown documents can't be rendered to dvi",
+            ))
+        }
+    };
+    let output_dir = match &options.output_dir {
+        Some(dir) => shell_quote(&dir.display().to_string()),
+        None => ".".into(),
+    };
+    Ok(Recipe {
+        uses: "Rmd",
+        generated: &["knit.md"],
+        script: format!(
+            "Rscript -e 'a <- commandArgs(TRUE); rmarkdown::render(a[1], output_format = a[2], output_dir = a[3])' %I {} {}",
+            format, output_dir
+        )
+        .into(),
+        requires: &["Rscript", "pandoc"],
+        ..Recipe::default()
+    })
+}
+
+/// Render the R markdown document at `file`
+///
+/// knitr writes figures to `<stem>_files/`, which is added to the generated files
+pub fn render(options: &Options, file: &Path, deps: &mut Deps) -> std::io::Result<()> {
+    let recipe = recipe(options)?;
+    let base = options.output_format().ext();
+    // Up to date checks only look next to the source, so with an output directory the
+    // document is always rendered
+    let target = match options.output_dir {
+        Some(_) => file.to_path_buf(),
+        None => file.with_extension(base),
+    };
+    recipe.run_for(&target, base, deps)?;
+    let stem = file.file_stem().map_or("", |s| s.to_str().unwrap_or(""));
+    let figures = format!("{}_files", stem);
+    let dirs = [
+        file.parent().map(|p| p.join(&figures)),
+        options.output_dir.as_ref().map(|dir| dir.join(&figures)),
+    ];
+    for dir in dirs.iter().flatten().filter(|d| d.is_dir()) {
+        deps.add_output(dir.clone());
+    }
+    Ok(())
+}
@@ -4,20 +4,89 @@
 // Distributed under terms of the MIT license.
 //
 
-use std::collections::HashMap;
-
-use crate::{recipe::Recipe, Options};
-
-pub fn make_cmds(_options: &Options, map: &mut HashMap<String, Recipe>) {
-    // sage
-    map.insert(
-        "sagetex.sout".into(),
-        Recipe {
-            uses: "sagetex.sage",
-            extras: &[],
-            generated: &["sagetex.sage.py", "sagetex.scmd"],
-            generated_dirs: &["sage-plots-for-"],
-            script: "sage \"%I\"".into(),
-        },
-    );
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    path::Path,
+    time::Duration,
+};
+
+use crate::{
+    recipe::{Recipe, RecipeProvider},
+    Options,
+};
+
+/// Read `path`, once more if it changed while it was being read
+///
+/// The engine may still be writing it, e.g. when a build was interrupted and restarted
+fn read_settled(path: &Path) -> std::io::Result<Vec<u8>> {
+    let before = std::fs::metadata(path)?.modified()?;
+    let contents = std::fs::read(path)?;
+    let after = std::fs::metadata(path)?;
+    if after.modified()? == before && after.len() == contents.len() as u64 {
+        return Ok(contents);
+    }
+    std::thread::sleep(Duration::from_millis(100));
+    std::fs::read(path)
+}
+
+/// Hash of a `.sagetex.sage`, or `None` if it can't be read
+///
+/// sagetex rewrites it on every latex pass, so the hash is what tells whether sage has to run
+/// again. Anything that isn't UTF-8 is hashed as a replacement character rather than failing.
+pub fn sage_digest(path: &Path) -> Option<u64> {
+    let contents = read_settled(path).ok()?;
+    let mut hasher = DefaultHasher::new();
+    for line in String::from_utf8_lossy(&contents).lines() {
+        line.hash(&mut hasher);
+    }
+    Some(hasher.finish())
+}
+
+/// Sagetex recipe
+pub struct Sage;
+
+impl RecipeProvider for Sage {
+    fn register(&self, _options: &Options, map: &mut HashMap<String, Recipe>) {
+        // sage
+        map.insert(
+            "sagetex.sout".into(),
+            Recipe {
+                uses: "sagetex.sage",
+                generated: &["sagetex.sage.py", "sagetex.scmd"],
+                generated_dirs: &["sage-plots-for-"],
+                script: "sage %I".into(),
+                precious: &["sagetex.sout"],
+                input_hash: Some(sage_digest),
+                requires: &["sage"],
+                ..Recipe::default()
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::TempDir;
+
+    #[test]
+    fn digest_of_non_utf8() {
+        let dir = TempDir::new("sage-test").unwrap();
+        let path = dir.path.join("paper.sagetex.sage");
+        std::fs::write(&path, b"x = 1\n\xff\nprint(x)\n").unwrap();
+        let digest = sage_digest(&path);
+        assert!(digest.is_some());
+        // Invalid bytes are all hashed the same, but the rest of the line still counts
+        std::fs::write(&path, b"x = 1\n\xfe\nprint(x)\n").unwrap();
+        assert_eq!(sage_digest(&path), digest);
+        std::fs::write(&path, b"x = 2\n\xfe\nprint(x)\n").unwrap();
+        assert_ne!(sage_digest(&path), digest);
+    }
+
+    #[test]
+    fn digest_of_missing_file() {
+        let dir = TempDir::new("sage-test").unwrap();
+        assert_eq!(sage_digest(&dir.path.join("missing.sagetex.sage")), None);
+    }
 }
@@ -0,0 +1,597 @@
+//
+// scan.rs
+// Copyright (C) 2021 matthew <matthew@matthew-ubuntu>
+// Distributed under terms of the MIT license.
+//
+
+//! Heuristic dependency scanner
+//!
+//! This reads the `.tex` source directly, so it can find dependencies before the engine has
+//! been run. It doesn't understand macros, so once a `.fls` exists it should be preferred.
+
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+};
+
+/// Commands taking a file name as their argument
+const COMMANDS: &[&str] = &[
+    "input",
+    "include",
+    "includegraphics",
+    "bibliography",
+    "pgfplotstableread",
+    "addplot",
+    "externaldocument",
+    "subfile",
+    "subfileinclude",
+];
+
+/// Commands from the `import` package, taking a directory and a file name in it
+///
+/// The `sub` variants take the directory relative to the current file's, the others relative to
+/// the main document's
+const IMPORTS: &[&str] = &[
+    "import",
+    "subimport",
+    "includefrom",
+    "subincludefrom",
+    "inputfrom",
+    "subinputfrom",
+];
+
+/// Extensions pdflatex tries, in order, for an `\includegraphics` without one
+pub(crate) const GRAPHICS_EXTS: &[&str] =
+    &["pdf", "png", "jpg", "mps", "jpeg", "jbig2", "jb2", "eps"];
+
+/// Remove `%` comments from `source`
+fn strip_comments(source: &str) -> String {
+    let mut ret = String::with_capacity(source.len());
+    for line in source.lines() {
+        let mut escaped = false;
+        let mut end = line.len();
+        for (i, c) in line.char_indices() {
+            match c {
+                '\\' => escaped = !escaped,
+                '%' if !escaped => {
+                    end = i;
+                    break;
+                }
+                _ => escaped = false,
+            }
+        }
+        ret.push_str(&line[..end]);
+        ret.push('\n');
+    }
+    ret
+}
+
+/// The argument at the start of `s`, and the rest of `s` after it
+fn argument(s: &str) -> Option<(&str, &str)> {
+    let arg = s.trim_start().strip_prefix('{')?;
+    let end = arg.find('}')?;
+    Some((arg[..end].trim(), &arg[end + 1..]))
+}
+
+/// Find the uses of [`COMMANDS`] and [`IMPORTS`] in `source`, as `(command, argument)`
+///
+/// Optional `[...]` arguments and starred variants are skipped over. For imports, the argument
+/// is the directory and file joined with a `/`.
+fn find_commands(source: &str) -> Vec<(&str, String)> {
+    let mut ret = vec![];
+    let mut cur = source;
+    while let Some(start) = cur.find('\\') {
+        cur = &cur[start + 1..];
+        let len = cur
+            .find(|c: char| !c.is_ascii_alphabetic())
+            .unwrap_or(cur.len());
+        let command = &cur[..len];
+        if IMPORTS.contains(&command) {
+            let rest = cur[len..].trim_start();
+            let rest = rest.strip_prefix('*').unwrap_or(rest);
+            if let Some((dir, rest)) = argument(rest) {
+                if let Some((file, _)) = argument(rest) {
+                    let dir = dir.trim_end_matches('/');
+                    if !file.is_empty() && !file.contains(['\n', '\\']) {
+                        ret.push((command, format!("{}/{}", dir, file)));
+                    }
+                }
+            }
+            continue;
+        }
+        if !COMMANDS.contains(&command) {
+            continue;
+        }
+        let mut rest = cur[len..].trim_start();
+        rest = rest.strip_prefix('*').unwrap_or(rest).trim_start();
+        if command == "addplot" {
+            // `\addplot`, `\addplot+` or `\addplot3`, then `[opts] table [opts] {file}`
+            rest = rest.strip_prefix('+').unwrap_or(rest);
+            rest = rest.strip_prefix('3').unwrap_or(rest);
+            rest = match skip_options(rest).and_then(|r| r.strip_prefix("table")) {
+                Some(rest) => rest,
+                None => continue,
+            };
+        }
+        rest = match skip_options(rest) {
+            Some(rest) => rest,
+            None => continue,
+        };
+        if let Some(arg) = rest.strip_prefix('{') {
+            if let Some(end) = arg.find('}') {
+                let arg = arg[..end].trim();
+                // Inline data rather than a file name
+                if !arg.is_empty() && !arg.contains(['\n', '\\']) {
+                    ret.push((command, arg.into()));
+                }
+            }
+        }
+    }
+    ret
+}
+
+/// The images `source` includes with `\includegraphics`
+pub(crate) fn graphics(source: &str) -> Vec<String> {
+    find_commands(&strip_comments(source))
+        .into_iter()
+        .filter(|(command, _)| *command == "includegraphics")
+        .map(|(_, name)| name)
+        .collect()
+}
+
+/// Skip over an optional `[...]` argument
+fn skip_options(s: &str) -> Option<&str> {
+    let s = s.trim_start();
+    if s.starts_with('[') {
+        s.find(']').map(|end| s[end + 1..].trim_start())
+    } else {
+        Some(s)
+    }
+}
+
+/// Add `ext` to `name` if it doesn't have an extension
+pub(crate) fn with_default_ext(name: &str, ext: &str) -> PathBuf {
+    let path = PathBuf::from(name);
+    if path.extension().is_some() {
+        path
+    } else {
+        path.with_extension(ext)
+    }
+}
+
+/// The file `\input{name}` reads, relative to `dir`
+///
+/// Like TeX, `.tex` is tried first even when the name already has an extension, so
+/// `\input{fig.tikz}` reads `fig.tikz.tex` if there is one, and `fig.tikz` otherwise
+pub(crate) fn resolve_input(dir: &Path, name: &str) -> PathBuf {
+    let path = dir.join(name);
+    if path.extension().is_some_and(|ext| ext == "tex") {
+        return path;
+    }
+    let tex = dir.join(format!("{}.tex", name));
+    if path.extension().is_none() || tex.exists() {
+        tex
+    } else {
+        path
+    }
+}
+
+/// The directories set with `\graphicspath{{dir/}{other/}}` in `source`
+fn graphics_path(source: &str) -> Vec<String> {
+    let rest = match source.find("\\graphicspath") {
+        Some(start) => source[start + "\\graphicspath".len()..].trim_start(),
+        None => return vec![],
+    };
+    let mut rest = match rest.strip_prefix('{') {
+        Some(rest) => rest,
+        None => return vec![],
+    };
+    let mut ret = vec![];
+    while let Some((dir, after)) = argument(rest) {
+        ret.push(dir.to_string());
+        rest = after;
+    }
+    ret
+}
+
+/// The image `\includegraphics{name}` refers to, looking in `dir` and then `graphics_path`
+///
+/// Without an extension, the ones the engine tries are tried in the same order. If nothing is
+/// found, the name relative to `dir` is returned.
+fn resolve_graphic(name: &str, dir: &Path, base: &Path, graphics_path: &[String]) -> PathBuf {
+    let dirs = std::iter::once(dir.to_path_buf()).chain(graphics_path.iter().map(|d| base.join(d)));
+    let has_ext = Path::new(name).extension().is_some();
+    for dir in dirs {
+        if has_ext && dir.join(name).is_file() {
+            return dir.join(name);
+        }
+        if let Some(found) = GRAPHICS_EXTS
+            .iter()
+            .map(|ext| dir.join(format!("{}.{}", name, ext)))
+            .find(|p| p.is_file())
+        {
+            return found;
+        }
+    }
+    dir.join(name)
+}
+
+/// Key for `path` in the seen set, so different spellings of the same file match
+fn canonical(path: &Path) -> PathBuf {
+    path.canonicalize().unwrap_or_else(|_| path.to_path_buf())
+}
+
+/// Scan `path`, which is included by each of `stack`
+///
+/// Paths are relative to `base`, the directory the engine runs in, or to `dir` once inside a
+/// file from the `import` package
+fn scan_file(
+    path: &Path,
+    base: &Path,
+    dir: &Path,
+    graphics_path: &[String],
+    deps: &mut Vec<PathBuf>,
+    seen: &mut HashSet<PathBuf>,
+    stack: &mut Vec<PathBuf>,
+) {
+    let source = match std::fs::read_to_string(path) {
+        Ok(source) => strip_comments(&source),
+        Err(_) => return,
+    };
+    stack.push(canonical(path));
+    for (command, arg) in find_commands(&source) {
+        // Not the including file's directory, unless it was imported
+        let relative_to = match command {
+            "import" | "includefrom" | "inputfrom" | "externaldocument" => base,
+            _ => dir,
+        };
+        let files = match command {
+            "bibliography" => arg
+                .split(',')
+                .map(|f| relative_to.join(with_default_ext(f.trim(), "bib")))
+                .collect(),
+            "includegraphics" => vec![resolve_graphic(&arg, dir, base, graphics_path)],
+            "pgfplotstableread" | "addplot" => vec![relative_to.join(&arg)],
+            // The xr package reads the other document's labels from its `.aux`
+            "externaldocument" => vec![relative_to.join(format!("{}.aux", arg))],
+            _ => vec![resolve_input(relative_to, &arg)],
+        };
+        for file in files {
+            let dep = crate::util::normalize(&file);
+            let key = canonical(&dep);
+            if stack.contains(&key) {
+                crate::message!(
+                    "{} {} includes {}, which forms a cycle, not following it",
+                    crate::util::yellow("Warning:"),
+                    crate::util::display_path(path),
+                    crate::util::display_path(&dep)
+                );
+                continue;
+            }
+            if !seen.insert(key) {
+                continue;
+            }
+            if matches!(command, "input" | "include") {
+                scan_file(&dep, base, dir, graphics_path, deps, seen, stack);
+            } else if IMPORTS.contains(&command) || command.starts_with("subfile") {
+                // Files the imported file includes are relative to it
+                let dir = dep.parent().unwrap_or(base).to_path_buf();
+                scan_file(&dep, base, &dir, graphics_path, deps, seen, stack);
+            }
+            deps.push(dep);
+        }
+    }
+    stack.pop();
+}
+
+/// Find the files the document at `path` depends on
+///
+/// Follows `\input`, `\include` and the `import` package's commands into other files, and
+/// reports `\includegraphics`, `\bibliography`, `\externaldocument` and pgfplots data table
+/// arguments. Inclusion cycles are reported and only followed once. Files included with
+/// `\subfile` are followed like `\subimport`, and a subfile depends on its main document, since
+/// that's where its preamble comes from.
+pub fn scan_tex_deps(path: &Path) -> Vec<PathBuf> {
+    let base = path.parent().unwrap_or_else(|| Path::new(""));
+    let mut deps = vec![];
+    let mut seen = HashSet::new();
+    seen.insert(canonical(path));
+    if let Some(main) = subfiles_main(path) {
+        seen.insert(canonical(&main));
+        deps.push(main);
+    }
+    // Usually set in the preamble
+    let graphics_path = std::fs::read_to_string(path)
+        .map(|source| graphics_path(&strip_comments(&source)))
+        .unwrap_or_default();
+    scan_file(
+        path,
+        base,
+        base,
+        &graphics_path,
+        &mut deps,
+        &mut seen,
+        &mut vec![],
+    );
+    deps
+}
+
+/// The class named by `\documentclass` in the file at `path`
+pub fn document_class(path: &Path) -> Option<String> {
+    let source = strip_comments(&std::fs::read_to_string(path).ok()?);
+    let rest = &source[source.find("\\documentclass")? + "\\documentclass".len()..];
+    let arg = skip_options(rest)?.strip_prefix('{')?;
+    Some(arg[..arg.find('}')?].trim().into())
+}
+
+/// The main document of the file at `path`, if it uses the `subfiles` class
+///
+/// This is the class option, as in `\documentclass[../main.tex]{subfiles}`, relative to the
+/// subfile
+pub fn subfiles_main(path: &Path) -> Option<PathBuf> {
+    let source = strip_comments(&std::fs::read_to_string(path).ok()?);
+    let rest = source[source.find("\\documentclass")? + "\\documentclass".len()..].trim_start();
+    let (main, rest) = match rest.strip_prefix('[') {
+        Some(options) => options.split_once(']')?,
+        None => return None,
+    };
+    let class = rest.trim_start().strip_prefix('{')?;
+    if class[..class.find('}')?].trim() != "subfiles" {
+        return None;
+    }
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+    Some(crate::util::normalize(&resolve_input(dir, main.trim())))
+}
+
+/// Sources of the figures `path` includes that use the `standalone` class
+///
+/// These are found from `\includegraphics{fig}` with a `fig.tex` next to it, or `\input{fig}`
+pub fn standalone_deps(path: &Path) -> Vec<PathBuf> {
+    let mut ret = vec![];
+    for dep in scan_tex_deps(path) {
+        let tex = dep.with_extension("tex");
+        if !ret.contains(&tex) && document_class(&tex).as_deref() == Some("standalone") {
+            ret.push(tex);
+        }
+    }
+    ret
+}
+
+/// Sources of the documents `path` reads labels from with `\externaldocument{other}`
+///
+/// Only the ones with an `other.tex` next to the `other.aux` are found, since the rest can't be
+/// built here
+pub fn external_documents(path: &Path) -> Vec<PathBuf> {
+    let mut ret = vec![];
+    for dep in scan_tex_deps(path) {
+        if dep.extension().is_some_and(|ext| ext == "aux") {
+            let tex = dep.with_extension("tex");
+            if !ret.contains(&tex) && tex.is_file() {
+                ret.push(tex);
+            }
+        }
+    }
+    ret
+}
+
+/// The packages the document at `path` loads with `\usepackage` or `\RequirePackage`
+///
+/// Only the document itself is read, since packages are usually loaded in the preamble
+pub fn packages(path: &Path) -> Vec<String> {
+    let source = match std::fs::read_to_string(path) {
+        Ok(source) => strip_comments(&source),
+        Err(_) => return vec![],
+    };
+    let mut ret = vec![];
+    for command in ["\\usepackage", "\\RequirePackage"] {
+        for rest in source.split(command).skip(1) {
+            let names = match skip_options(rest).and_then(argument) {
+                Some((names, _)) => names,
+                None => continue,
+            };
+            for name in names.split(',').map(str::trim) {
+                if !name.is_empty() && !ret.iter().any(|n| n == name) {
+                    ret.push(name.to_string());
+                }
+            }
+        }
+    }
+    ret
+}
+
+/// Whether the document at `path` switches to `\batchmode` itself
+///
+/// The engine then stops printing to the terminal, whatever `-interaction` says
+pub fn sets_batchmode(path: &Path) -> bool {
+    let source = match std::fs::read_to_string(path) {
+        Ok(source) => strip_comments(&source),
+        Err(_) => return false,
+    };
+    source
+        .split("\\batchmode")
+        .skip(1)
+        .any(|rest| !rest.starts_with(|c: char| c.is_ascii_alphabetic()))
+}
+
+/// Read a `% !TEX key = value` magic comment from the start of the file at `path`
+///
+/// Keys are case insensitive, and only the leading comment lines are searched
+pub fn magic_comment(path: &Path, key: &str) -> Option<String> {
+    let source = std::fs::read_to_string(path).ok()?;
+    for line in source.lines() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let comment = line.strip_prefix('%')?.trim_start();
+        let directive = match comment.get(..4) {
+            Some(tex) if tex.eq_ignore_ascii_case("!TEX") => comment[4..].trim_start(),
+            _ => continue,
+        };
+        if let Some((k, v)) = directive.split_once('=') {
+            if k.trim().eq_ignore_ascii_case(key) {
+                return Some(v.trim().into());
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::TempDir;
+
+    #[test]
+    fn input_cycle() {
+        let dir = TempDir::new("scan-test").unwrap();
+        let a = dir.path.join("a.tex");
+        let b = dir.path.join("b.tex");
+        std::fs::write(&a, "\\input{b}\n\\input{a}\n").unwrap();
+        std::fs::write(&b, "\\input{a}\n\\includegraphics{fig.png}\n").unwrap();
+        // Terminates, and follows each file once
+        assert_eq!(
+            scan_tex_deps(&a),
+            vec![dir.path.join("fig.png"), dir.path.join("b.tex")]
+        );
+    }
+
+    #[test]
+    fn input_without_extension() {
+        let dir = TempDir::new("scan-test").unwrap();
+        assert_eq!(resolve_input(&dir.path, "foo"), dir.path.join("foo.tex"));
+        assert_eq!(
+            resolve_input(&dir.path, "foo.tex"),
+            dir.path.join("foo.tex")
+        );
+    }
+
+    #[test]
+    fn input_with_other_extension() {
+        let dir = TempDir::new("scan-test").unwrap();
+        assert_eq!(
+            resolve_input(&dir.path, "fig.tikz"),
+            dir.path.join("fig.tikz")
+        );
+        // `.tex` is tried first, like TeX does
+        std::fs::write(dir.path.join("fig.tikz.tex"), "").unwrap();
+        assert_eq!(
+            resolve_input(&dir.path, "fig.tikz"),
+            dir.path.join("fig.tikz.tex")
+        );
+    }
+
+    #[test]
+    fn graphic_without_extension() {
+        let dir = TempDir::new("scan-test").unwrap();
+        let d = &dir.path;
+        // Nothing found, so the name is kept as written
+        assert_eq!(resolve_graphic("fig", d, d, &[]), d.join("fig"));
+        std::fs::write(d.join("fig.png"), "").unwrap();
+        assert_eq!(resolve_graphic("fig", d, d, &[]), d.join("fig.png"));
+        // In the order the engine tries them
+        std::fs::write(d.join("fig.pdf"), "").unwrap();
+        assert_eq!(resolve_graphic("fig", d, d, &[]), d.join("fig.pdf"));
+        assert_eq!(resolve_graphic("fig.png", d, d, &[]), d.join("fig.png"));
+    }
+
+    #[test]
+    fn graphic_in_graphics_path() {
+        let dir = TempDir::new("scan-test").unwrap();
+        let d = &dir.path;
+        std::fs::create_dir(d.join("images")).unwrap();
+        std::fs::write(d.join("images/plot.jpg"), "").unwrap();
+        let paths = graphics_path("\\graphicspath{{figures/}{images/}}");
+        assert_eq!(paths, vec!["figures/", "images/"]);
+        assert_eq!(
+            resolve_graphic("plot", d, d, &paths),
+            d.join("images/plot.jpg")
+        );
+        assert_eq!(resolve_graphic("plot", d, d, &[]), d.join("plot"));
+    }
+
+    #[test]
+    fn input_relative_to_document() {
+        let dir = TempDir::new("scan-test").unwrap();
+        let sub = dir.path.join("sub");
+        std::fs::create_dir_all(sub.join("parts")).unwrap();
+        let doc = sub.join("doc.tex");
+        std::fs::write(&doc, "\\input{intro}\n\\input{parts/one}\n").unwrap();
+        std::fs::write(sub.join("intro.tex"), "").unwrap();
+        // Relative to the document, even from a file it includes
+        std::fs::write(sub.join("parts/one.tex"), "\\includegraphics{fig.png}\n").unwrap();
+        assert_eq!(
+            scan_tex_deps(&doc),
+            vec![
+                sub.join("intro.tex"),
+                sub.join("fig.png"),
+                sub.join("parts/one.tex")
+            ]
+        );
+    }
+
+    #[test]
+    fn input_outside_document_dir() {
+        let dir = TempDir::new("scan-test").unwrap();
+        std::fs::create_dir_all(dir.path.join("sub")).unwrap();
+        std::fs::create_dir_all(dir.path.join("shared")).unwrap();
+        let doc = dir.path.join("sub/doc.tex");
+        std::fs::write(&doc, "\\input{../shared/defs}\n").unwrap();
+        std::fs::write(dir.path.join("shared/defs.tex"), "").unwrap();
+        assert_eq!(scan_tex_deps(&doc), vec![dir.path.join("shared/defs.tex")]);
+    }
+
+    #[test]
+    fn nested_subimport() {
+        let dir = TempDir::new("scan-test").unwrap();
+        let d = &dir.path;
+        let sections = d.join("chapters/sections");
+        std::fs::create_dir_all(&sections).unwrap();
+        std::fs::create_dir_all(d.join("shared")).unwrap();
+        let doc = d.join("doc.tex");
+        std::fs::write(&doc, "\\subimport{chapters/}{one}\n").unwrap();
+        // Each `\subimport` is relative to the file it's in, and `\import` to the main document
+        std::fs::write(
+            d.join("chapters/one.tex"),
+            "\\subimport{sections/}{intro}\n\\import{shared/}{defs}\n",
+        )
+        .unwrap();
+        // Plain inputs are relative to the directory the file was imported from
+        std::fs::write(
+            sections.join("intro.tex"),
+            "\\input{body}\n\\includegraphics{fig.png}\n",
+        )
+        .unwrap();
+        std::fs::write(sections.join("body.tex"), "").unwrap();
+        std::fs::write(d.join("shared/defs.tex"), "").unwrap();
+        let mut deps = scan_tex_deps(&doc);
+        deps.sort();
+        assert_eq!(
+            deps,
+            vec![
+                d.join("chapters/one.tex"),
+                sections.join("body.tex"),
+                sections.join("fig.png"),
+                sections.join("intro.tex"),
+                d.join("shared/defs.tex"),
+            ]
+        );
+    }
+
+    #[test]
+    fn batchmode() {
+        let dir = TempDir::new("scan-test").unwrap();
+        let doc = dir.path.join("doc.tex");
+        let sets = |source: &str| {
+            std::fs::write(&doc, source).unwrap();
+            sets_batchmode(&doc)
+        };
+        assert!(sets("\\batchmode\n\\documentclass{article}\n"));
+        assert!(sets(
+            "\\documentclass{article}\\batchmode\\begin{document}\n"
+        ));
+        assert!(!sets("% \\batchmode\n\\documentclass{article}\n"));
+        assert!(!sets("\\batchmodefalse\n\\nonstopmode\n"));
+        assert!(!sets_batchmode(&dir.path.join("missing.tex")));
+    }
+}
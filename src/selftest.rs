@@ -0,0 +1,117 @@
+//
+// selftest.rs
+// Copyright (C) 2021 matthew <matthew@matthew-ubuntu>
+// Distributed under terms of the MIT license.
+//
+
+//! End to end check of the installation, by building a small bundled document
+
+use std::{
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use clap::Clap;
+
+use crate::{message, util, Options};
+
+const DOCUMENT: &str = r"\documentclass{article}
+\begin{document}
+Hello, world! See \cite{knuth} and Figure~\ref{fig:box}.
+
+\begin{figure}[h]
+    \centering
+    \fbox{\rule{2cm}{1cm}}
+    \caption{A box}
+    \label{fig:box}
+\end{figure}
+
+\bibliographystyle{plain}
+\bibliography{selftest}
+\end{document}
+";
+
+const BIBLIOGRAPHY: &str = r"@book{knuth,
+    author = {Donald E. Knuth},
+    title = {The {\TeX}book},
+    publisher = {Addison-Wesley},
+    year = {1984},
+}
+";
+
+/// Result of a single stage
+struct Stage {
+    name: &'static str,
+    passed: bool,
+    time: Duration,
+}
+
+fn stage(stages: &mut Vec<Stage>, name: &'static str, f: impl FnOnce() -> bool) -> bool {
+    let start = Instant::now();
+    let passed = f();
+    stages.push(Stage {
+        name,
+        passed,
+        time: start.elapsed(),
+    });
+    passed
+}
+
+fn run_stages(dir: &Path, stages: &mut Vec<Stage>) -> bool {
+    let has_tool = |tool| util::find_program(tool).is_some();
+    stage(stages, "pdflatex installed", || has_tool("pdflatex"))
+        && stage(stages, "bibtex installed", || has_tool("bibtex"))
+        && stage(stages, "write document", || {
+            std::fs::write(dir.join("selftest.tex"), DOCUMENT).is_ok()
+                && std::fs::write(dir.join("selftest.bib"), BIBLIOGRAPHY).is_ok()
+        })
+        && stage(stages, "build", || {
+            // The build expects to be run next to the document
+            let cwd = match std::env::current_dir() {
+                Ok(cwd) => cwd,
+                Err(_) => return false,
+            };
+            if std::env::set_current_dir(dir).is_err() {
+                return false;
+            }
+            let report = Options::try_parse_from(["latexmk", "--force", "selftest.tex"])
+                .map(|options| crate::build(&options));
+            let _ = std::env::set_current_dir(cwd);
+            report.is_ok_and(|r| r.success)
+        })
+        && stage(stages, "bibliography", || dir.join("selftest.bbl").exists())
+        && stage(stages, "references resolved", || {
+            std::fs::read_to_string(dir.join("selftest.log"))
+                .is_ok_and(|log| !log.contains("undefined"))
+        })
+        && stage(stages, "pdf produced", || dir.join("selftest.pdf").exists())
+}
+
+/// Build the bundled document in a temporary directory, and print a summary
+///
+/// Returns whether every stage passed
+pub fn run() -> std::io::Result<bool> {
+    let dir = std::env::temp_dir().join(format!("latexmk-selftest-{}", std::process::id()));
+    std::fs::create_dir_all(&dir)?;
+    let mut stages = vec![];
+    let passed = run_stages(&dir, &mut stages);
+    std::fs::remove_dir_all(&dir)?;
+
+    message!();
+    message!("Selftest summary:");
+    for stage in stages.iter() {
+        message!(
+            "  [{}] {} ({:.2}s)",
+            if stage.passed { "pass" } else { "FAIL" },
+            stage.name,
+            stage.time.as_secs_f64()
+        );
+    }
+    let total: Duration = stages.iter().map(|s| s.time).sum();
+    message!(
+        "Selftest {} in {:.2}s",
+        if passed { "passed" } else { "failed" },
+        total.as_secs_f64()
+    );
+    Ok(passed)
+}
@@ -0,0 +1,139 @@
+//
+// serve.rs
+// Copyright (C) 2021 matthew <matthew@matthew-ubuntu>
+// Distributed under terms of the MIT license.
+//
+
+//! Reporting the state of `--watch` over HTTP, for `--serve`
+//!
+//! Editors can poll these instead of parsing the output:
+//!
+//! - `/status`: whether a build is running, and whether the last one succeeded
+//! - `/diagnostics`: the errors of the last build, and the files it couldn't find
+//! - `/deps`: the files the last build read
+//!
+//! Each returns a JSON object. The server only listens on localhost, and only answers `GET`.
+
+use std::{
+    io::{BufRead, BufReader, Write},
+    net::{Ipv4Addr, TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use serde_json::json;
+
+use crate::{message, recipe::BuildReport, util};
+
+/// What the server knows about the builds
+#[derive(Debug, Default)]
+struct State {
+    building: bool,
+    builds: usize,
+    report: Option<BuildReport>,
+}
+
+/// Handle to update what the server reports
+#[derive(Debug, Clone)]
+pub struct Server {
+    state: Arc<Mutex<State>>,
+}
+
+impl Server {
+    /// Start answering requests on `port` of localhost, in the background
+    pub fn start(port: u16) -> std::io::Result<Self> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))?;
+        let server = Self {
+            state: Arc::default(),
+        };
+        let state = server.state.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                if let Err(e) = answer(stream, &state) {
+                    message!("{} --serve: {}", util::yellow("Warning:"), e);
+                }
+            }
+        });
+        Ok(server)
+    }
+
+    /// Report that a build started
+    pub fn building(&self) {
+        self.state.lock().unwrap().building = true;
+    }
+
+    /// Report the outcome of the build that just finished
+    pub fn finished(&self, report: BuildReport) {
+        let mut state = self.state.lock().unwrap();
+        state.building = false;
+        state.builds += 1;
+        state.report = Some(report);
+    }
+}
+
+/// The body for `path`, or `None` if there's no such endpoint
+fn body(path: &str, state: &State) -> Option<serde_json::Value> {
+    let report = state.report.as_ref();
+    let paths = |paths: Option<&Vec<std::path::PathBuf>>| -> Vec<String> {
+        paths
+            .into_iter()
+            .flatten()
+            .map(|p| util::display_path(p))
+            .collect()
+    };
+    Some(match path {
+        "/status" => json!({
+            "building": state.building,
+            "builds": state.builds,
+            "success": report.map(|r| r.success),
+            "tool_not_found": report.is_some_and(|r| r.tool_not_found),
+            "outputs": paths(report.map(|r| &r.outputs)),
+        }),
+        "/diagnostics" => json!({
+            "diagnostics": report.map_or(&[][..], |r| &r.diagnostics[..]),
+            "missing": paths(report.map(|r| &r.missing)),
+        }),
+        "/deps" => json!({
+            "inputs": paths(report.map(|r| &r.inputs)),
+        }),
+        _ => return None,
+    })
+}
+
+/// Answer the request on `stream`
+fn answer(mut stream: TcpStream, state: &Mutex<State>) -> std::io::Result<()> {
+    // Requests are answered one at a time, so a client that never finishes can't hold it up
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = BufReader::new(&stream);
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    // The headers aren't needed, but clients expect them to be read
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+    let mut parts = request.split_whitespace();
+    let method = parts.next().unwrap_or("");
+    let path = parts.next().unwrap_or("");
+    let path = path.split('?').next().unwrap_or(path);
+    let (code, body) = if method != "GET" {
+        (
+            "405 Method Not Allowed",
+            json!({ "error": "only GET is supported" }),
+        )
+    } else {
+        match body(path, &state.lock().unwrap()) {
+            Some(body) => ("200 OK", body),
+            None => ("404 Not Found", json!({ "error": "no such endpoint" })),
+        }
+    };
+    let body = body.to_string();
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        code,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
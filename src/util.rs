@@ -0,0 +1,655 @@
+//
+// util.rs
+// Copyright (C) 2021 matthew <matthew@matthew-ubuntu>
+// Distributed under terms of the MIT license.
+//
+
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    io::{self, BufRead, IsTerminal, Read, Write},
+    path::{Component, Path, PathBuf},
+    process::{Child, Command, Output, Stdio},
+    str::FromStr,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    time::SystemTime,
+};
+
+/// Whether messages are colored, set once from `--color`
+static COLOR: AtomicBool = AtomicBool::new(false);
+
+/// Whether commands are printed before they're run, set from `--trace`
+static TRACE: AtomicBool = AtomicBool::new(false);
+
+/// Whether messages are printed to stderr, set from `--stdout-pdf`
+static MESSAGES_TO_STDERR: AtomicBool = AtomicBool::new(false);
+
+/// Whether the engine's output is shown unfiltered, set from `--raw-log`
+static RAW_LOG: AtomicBool = AtomicBool::new(false);
+
+/// Whether paths in messages are shown relative to the current directory, unset by
+/// `--no-relative-paths`
+static RELATIVE_PATHS: AtomicBool = AtomicBool::new(true);
+
+/// Show the engine's output as is, instead of filtering it with [`filter_engine_log`]
+pub fn set_raw_log(raw: bool) {
+    RAW_LOG.store(raw, Ordering::Relaxed);
+}
+
+/// The output of a failed engine run, to show
+///
+/// Unless `--raw-log` was given, this is filtered with [`filter_engine_log`]
+pub fn engine_log(log: &[u8]) -> String {
+    let log = String::from_utf8_lossy(log);
+    if RAW_LOG.load(Ordering::Relaxed) {
+        log.into_owned()
+    } else {
+        filter_engine_log(&log)
+    }
+}
+
+/// Whether `s`, following a `(`, is a file the engine opened
+fn is_file_open(s: &str) -> bool {
+    let mut chars = s.chars();
+    match (chars.next(), chars.next()) {
+        (Some('/'), _) | (Some('.'), Some('/' | '.')) => true,
+        // e.g. `C:/texlive/...`
+        (Some(c), Some(':')) => c.is_ascii_alphabetic(),
+        _ => false,
+    }
+}
+
+/// Remove the routine parts of the engine's output, keeping the warnings & errors
+///
+/// TeX prints `(path` when it opens a file, and `)` when it's done with it, which makes up most
+/// of its output. Those, and blank lines, are removed.
+pub fn filter_engine_log(log: &str) -> String {
+    let mut ret = String::new();
+    for line in log.lines() {
+        let mut kept = String::new();
+        let mut rest = line;
+        while let Some(start) = rest.find('(') {
+            kept.push_str(&rest[..start]);
+            let after = &rest[start + 1..];
+            if is_file_open(after) {
+                let end = after
+                    .find(|c: char| c.is_whitespace() || c == ')' || c == '(')
+                    .unwrap_or(after.len());
+                rest = &after[end..];
+            } else {
+                kept.push('(');
+                rest = after;
+            }
+        }
+        kept.push_str(rest);
+        // Only closing parens left
+        if kept.chars().all(|c| c == ')' || c.is_whitespace()) {
+            continue;
+        }
+        ret.push_str(kept.trim_start_matches([')', ' ']));
+        ret.push('\n');
+    }
+    ret
+}
+
+/// Print messages to stderr instead of stdout, so stdout only has the output
+pub fn messages_to_stderr(enabled: bool) {
+    MESSAGES_TO_STDERR.store(enabled, Ordering::Relaxed);
+}
+
+/// Where messages are printed
+///
+/// Locked, so a message written in several parts isn't interleaved with others
+pub fn messages() -> Box<dyn Write> {
+    if MESSAGES_TO_STDERR.load(Ordering::Relaxed) {
+        Box::new(io::stderr().lock())
+    } else {
+        Box::new(io::stdout().lock())
+    }
+}
+
+/// Where programs whose output is shown as it's printed should write to
+pub fn messages_stdio() -> Stdio {
+    if MESSAGES_TO_STDERR.load(Ordering::Relaxed) {
+        io::stderr().into()
+    } else {
+        Stdio::inherit()
+    }
+}
+
+/// Print a line with [`message!`]
+pub fn print_message(args: std::fmt::Arguments<'_>) {
+    // There's nowhere left to report to if this fails
+    let _ = writeln!(messages(), "{}", args);
+}
+
+/// Like `println!`, but printed to stderr when stdout is used for the output
+macro_rules! message {
+    () => {
+        $crate::util::print_message(format_args!(""))
+    };
+    ($($arg:tt)*) => {
+        $crate::util::print_message(format_args!($($arg)*))
+    };
+}
+pub(crate) use message;
+
+/// Print every command before running it
+pub fn set_trace(trace: bool) {
+    TRACE.store(trace, Ordering::Relaxed);
+}
+
+/// Print `cmd` as it could be run by hand, if tracing
+fn trace(cmd: &Command) {
+    if !TRACE.load(Ordering::Relaxed) {
+        return;
+    }
+    let mut line = String::from("+");
+    if let Some(dir) = cmd.get_current_dir() {
+        line.push_str(&format!(" cd {} &&", shell_quote(&dir.to_string_lossy())));
+    }
+    for (key, value) in cmd.get_envs() {
+        if let Some(value) = value {
+            line.push_str(&format!(
+                " {}={}",
+                key.to_string_lossy(),
+                shell_quote(&value.to_string_lossy())
+            ));
+        }
+    }
+    line.push(' ');
+    line.push_str(&shell_quote(&cmd.get_program().to_string_lossy()));
+    for arg in cmd.get_args() {
+        line.push(' ');
+        line.push_str(&shell_quote(&arg.to_string_lossy()));
+    }
+    crate::message!("{}", line);
+}
+
+/// Where `program` is found on the `PATH`, or `None` if it isn't installed
+pub fn find_program(program: &str) -> Option<PathBuf> {
+    let path = std::env::var_os("PATH")?;
+    std::env::split_paths(&path).find_map(|dir| {
+        let exe = dir.join(program);
+        if exe.is_file() {
+            return Some(exe);
+        }
+        let exe = exe.with_extension("exe");
+        Some(exe).filter(|exe| cfg!(windows) && exe.is_file())
+    })
+}
+
+/// When `path` was last modified, or `None` if that can't be read
+pub fn modified(path: &Path) -> Option<SystemTime> {
+    path.metadata().ok()?.modified().ok()
+}
+
+/// Run `cmd` to completion, collecting its output
+///
+/// All commands are run through this, [`output_with`] or [`spawn`], so `--trace` shows every one
+pub fn output(cmd: &mut Command) -> io::Result<Output> {
+    trace(cmd);
+    cmd.output()
+}
+
+/// Same as [`output`], but calling `on_line` with each line of stdout as soon as it's printed
+pub fn output_with(cmd: &mut Command, mut on_line: impl FnMut(&str)) -> io::Result<Output> {
+    trace(cmd);
+    let mut child = cmd.stdout(Stdio::piped()).stderr(Stdio::piped()).spawn()?;
+    let mut stderr = child.stderr.take().expect("stderr is piped");
+    // Read at the same time, so neither pipe fills up while the other is waited on
+    let errors = std::thread::spawn(move || {
+        let mut buf = vec![];
+        stderr.read_to_end(&mut buf).map(|_| buf)
+    });
+    let mut stdout = vec![];
+    let mut reader = io::BufReader::new(child.stdout.take().expect("stdout is piped"));
+    loop {
+        let start = stdout.len();
+        if reader.read_until(b'\n', &mut stdout)? == 0 {
+            break;
+        }
+        on_line(&String::from_utf8_lossy(&stdout[start..]));
+    }
+    let stderr = errors.join().expect("Reading stderr panicked")?;
+    Ok(Output {
+        status: child.wait()?,
+        stdout,
+        stderr,
+    })
+}
+
+/// Start `cmd` in the background
+pub fn spawn(cmd: &mut Command) -> io::Result<Child> {
+    trace(cmd);
+    cmd.spawn()
+}
+
+/// When to color messages
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorChoice {
+    /// Only when printing to a terminal
+    Auto,
+    Always,
+    Never,
+}
+
+impl FromStr for ColorChoice {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(Self::Auto),
+            "always" => Ok(Self::Always),
+            "never" => Ok(Self::Never),
+            _ => Err(format!(
+                "unknown color choice `{}`, expected auto, always or never",
+                s
+            )),
+        }
+    }
+}
+
+/// Decide whether to color messages
+///
+/// Setting `NO_COLOR` (see <https://no-color.org>) disables color, even with `--color always`
+pub fn init_color(choice: ColorChoice) {
+    let no_color = std::env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty());
+    let enabled = color_enabled(choice, no_color, io::stdout().is_terminal());
+    COLOR.store(enabled, Ordering::Relaxed);
+}
+
+/// Whether to color messages for `choice`, when `NO_COLOR` is set or not, and messages are
+/// printed to a terminal or not
+fn color_enabled(choice: ColorChoice, no_color: bool, terminal: bool) -> bool {
+    match choice {
+        _ if no_color => false,
+        ColorChoice::Auto => terminal,
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+    }
+}
+
+fn paint(code: &str, s: &str) -> String {
+    if COLOR.load(Ordering::Relaxed) {
+        format!("\x1b[{}m{}\x1b[0m", code, s)
+    } else {
+        s.into()
+    }
+}
+
+/// `s` in red, for errors
+pub fn red(s: &str) -> String {
+    paint("31", s)
+}
+
+/// `s` in yellow, for warnings
+pub fn yellow(s: &str) -> String {
+    paint("33", s)
+}
+
+/// Quote `s` so the shell treats it as a single word
+pub fn shell_quote(s: &str) -> String {
+    if !s.is_empty()
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_-./+,:=@".contains(c))
+    {
+        s.into()
+    } else {
+        format!("'{}'", s.replace('\'', "'\\''"))
+    }
+}
+
+/// Show paths in messages relative to the current directory, or as they are
+pub fn set_relative_paths(relative: bool) {
+    RELATIVE_PATHS.store(relative, Ordering::Relaxed);
+}
+
+/// `path` relative to the current directory, if it's inside it, for messages
+///
+/// Paths are shown as they are after `set_relative_paths(false)`. Where the options are at
+/// hand, [`Options::display_path`](crate::Options::display_path) is used instead.
+pub fn display_path(path: &Path) -> String {
+    let relative = std::env::current_dir()
+        .ok()
+        .filter(|_| RELATIVE_PATHS.load(Ordering::Relaxed))
+        .and_then(|cwd| path.strip_prefix(cwd).ok().map(Path::to_path_buf));
+    match relative {
+        Some(relative) if relative.as_os_str().is_empty() => ".".into(),
+        Some(relative) => relative.display().to_string(),
+        None => path.display().to_string(),
+    }
+}
+
+/// `path` with `.` and `..` components removed, without touching the file system
+///
+/// This can be wrong if the path goes through symlinks, but works for files that don't exist
+pub fn normalize(path: &Path) -> PathBuf {
+    let mut ret = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => (),
+            Component::ParentDir if ret.file_name().is_some() => {
+                ret.pop();
+            }
+            component => ret.push(component),
+        }
+    }
+    ret
+}
+
+/// Hash of the contents of the file at `path`, or `None` if it can't be read
+pub fn hash_file(path: &Path) -> Option<u64> {
+    let contents = std::fs::read(path).ok()?;
+    let mut hasher = DefaultHasher::new();
+    contents.hash(&mut hasher);
+    Some(hasher.finish())
+}
+
+/// Set the modification time of `path` to `time`, creating it if it doesn't exist
+pub fn touch(path: &Path, time: SystemTime) -> io::Result<()> {
+    std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?
+        .set_modified(time)
+}
+
+/// Today's date (UTC) as `YYYY-MM-DD`
+pub fn today() -> String {
+    let secs = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    // Days since 1970-01-01 to a civil date, see http://howardhinnant.github.io/date_algorithms.html
+    let z = (secs / 86400) as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// Short hash of the current git commit
+pub fn git_hash() -> Option<String> {
+    let output = self::output(Command::new("git").args(["rev-parse", "--short", "HEAD"])).ok()?;
+    if output.status.success() {
+        Some(String::from_utf8_lossy(&output.stdout).trim().to_string())
+    } else {
+        None
+    }
+}
+
+/// Template for the name of the final output
+///
+/// # Placeholders
+/// - `{stem}`: The filename without an extension
+/// - `{date}`: Today's date, as `YYYY-MM-DD`
+/// - `{engine}`: The engine used to build the output
+/// - `{git}`: The short hash of the current git commit
+#[derive(Debug, Clone)]
+pub struct OutputName(String);
+
+impl OutputName {
+    const PLACEHOLDERS: &'static [&'static str] = &["stem", "date", "engine", "git"];
+
+    /// Fill in the placeholders
+    pub fn render(&self, stem: &str, engine: &str) -> String {
+        let mut ret = self
+            .0
+            .replace("{stem}", stem)
+            .replace("{date}", &today())
+            .replace("{engine}", engine);
+        if ret.contains("{git}") {
+            ret = ret.replace("{git}", &git_hash().unwrap_or_else(|| "unknown".into()));
+        }
+        ret
+    }
+}
+
+impl FromStr for OutputName {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut cur = s;
+        while let Some(start) = cur.find('{') {
+            let rest = &cur[start + 1..];
+            let end = rest
+                .find('}')
+                .ok_or_else(|| format!("unclosed `{{` in `{}`", s))?;
+            if !Self::PLACEHOLDERS.contains(&&rest[..end]) {
+                return Err(format!(
+                    "unknown placeholder `{{{}}}`, expected one of {{{}}}",
+                    &rest[..end],
+                    Self::PLACEHOLDERS.join("}, {")
+                ));
+            }
+            cur = &rest[end + 1..];
+        }
+        if s.is_empty() {
+            return Err("output name can't be empty".into());
+        }
+        Ok(Self(s.into()))
+    }
+}
+
+/// Copy `from` over `to`, so that `to` is never partially written
+///
+/// The copy is written next to `to` first, and then renamed over it
+pub fn replace_file(from: &Path, to: &Path) -> io::Result<()> {
+    let mut partial = to.as_os_str().to_owned();
+    partial.push(".partial");
+    let partial = PathBuf::from(partial);
+    std::fs::copy(from, &partial)?;
+    std::fs::rename(&partial, to).inspect_err(|_| {
+        let _ = std::fs::remove_file(&partial);
+    })
+}
+
+/// Copy the file at `from` to `to`, keeping its modification time
+///
+/// Copies of the sources are compared against the outputs built from them, so they have to
+/// look as old as the originals
+pub fn copy_file(from: &Path, to: &Path) -> io::Result<()> {
+    std::fs::copy(from, to)?;
+    let modified = from.metadata()?.modified()?;
+    // The copy keeps the permissions, so it may be read only
+    std::fs::File::options()
+        .write(true)
+        .open(to)
+        .or_else(|_| std::fs::File::open(to))?
+        .set_modified(modified)
+}
+
+/// Recursively copy the contents of `from` into `to`, skipping hidden files
+pub fn copy_dir(from: &Path, to: &Path) -> io::Result<()> {
+    std::fs::create_dir_all(to)?;
+    let skip = to.canonicalize()?;
+    for entry in from.read_dir()? {
+        let entry = entry?;
+        let path = entry.path();
+        if entry.file_name().to_string_lossy().starts_with('.') || path.canonicalize()? == skip {
+            continue;
+        }
+        if entry.file_type()?.is_dir() {
+            copy_dir(&path, &to.join(entry.file_name()))?;
+        } else {
+            copy_file(&path, &to.join(entry.file_name()))?;
+        }
+    }
+    Ok(())
+}
+
+/// Temporary directory, which is removed when dropped unless `keep` is set
+#[derive(Debug)]
+pub struct TempDir {
+    pub path: PathBuf,
+    pub keep: bool,
+}
+
+/// Number of temporary directories created so far, to give each one a different name
+static TEMP_DIRS: AtomicUsize = AtomicUsize::new(0);
+
+impl TempDir {
+    /// Create a new, empty, temporary directory with `prefix` in its name
+    ///
+    /// Anything already at the name it picks was made by someone else, so it's never reused or
+    /// removed. Instead, another name is tried.
+    pub fn new(prefix: &str) -> io::Result<Self> {
+        let mut builder = std::fs::DirBuilder::new();
+        #[cfg(unix)]
+        std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+        for _ in 0..16 {
+            let nanos = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map_or(0, |d| d.subsec_nanos());
+            let path = std::env::temp_dir().join(format!(
+                "latexmk-{}-{}-{}-{:08x}",
+                prefix,
+                std::process::id(),
+                TEMP_DIRS.fetch_add(1, Ordering::Relaxed),
+                nanos
+            ));
+            match builder.create(&path) {
+                Ok(()) => return Ok(Self { path, keep: false }),
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            "couldn't find an unused name for a temporary directory",
+        ))
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        if self.keep {
+            crate::message!("Kept temporary directory {}", display_path(&self.path));
+        } else if std::fs::remove_dir_all(&self.path).is_err() {
+            crate::message!("Couldn't remove {}", display_path(&self.path));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// What the shell makes of `word`
+    fn shell_words(word: &str) -> String {
+        let output = Command::new("bash")
+            .arg("-c")
+            .arg(format!("printf '%s|' {}", word))
+            .output()
+            .unwrap();
+        String::from_utf8(output.stdout).unwrap()
+    }
+
+    #[test]
+    fn quote_plain_names() {
+        assert_eq!(shell_quote("paper.tex"), "paper.tex");
+        assert_eq!(shell_quote("dir/my-paper_2.tex"), "dir/my-paper_2.tex");
+        assert_eq!(shell_quote(""), "''");
+    }
+
+    #[test]
+    fn quote_names_with_spaces() {
+        assert_eq!(shell_quote("My Thesis.tex"), "'My Thesis.tex'");
+        assert_eq!(shell_words(&shell_quote("My Thesis.tex")), "My Thesis.tex|");
+        assert_eq!(shell_words(&shell_quote(" a  b ")), " a  b |");
+    }
+
+    #[test]
+    fn quote_names_with_quotes() {
+        assert_eq!(shell_quote("it's.tex"), "'it'\\''s.tex'");
+        for name in ["it's.tex", "\"quoted\".tex", "'", "a'b\"c"] {
+            assert_eq!(shell_words(&shell_quote(name)), format!("{}|", name));
+        }
+    }
+
+    #[test]
+    fn quote_shell_syntax() {
+        for name in [
+            "$(touch x).tex",
+            "`id`.tex",
+            "a;b.tex",
+            "a\\b.tex",
+            "*.tex",
+            "a\nb.tex",
+        ] {
+            assert_eq!(shell_words(&shell_quote(name)), format!("{}|", name));
+        }
+    }
+
+    #[test]
+    fn normalize_parent_dirs() {
+        assert_eq!(normalize(Path::new("sub/../doc.tex")), Path::new("doc.tex"));
+        assert_eq!(
+            normalize(Path::new("/work/src/./../shared/defs.tex")),
+            Path::new("/work/shared/defs.tex")
+        );
+        // Nothing to remove them from, so they're kept
+        assert_eq!(
+            normalize(Path::new("../shared/defs.tex")),
+            Path::new("../shared/defs.tex")
+        );
+        assert_eq!(normalize(Path::new("a/../../b")), Path::new("../b"));
+    }
+
+    #[test]
+    fn paths_relative_to_cwd() {
+        let cwd = std::env::current_dir().unwrap();
+        let path = cwd.join("src/paper.tex");
+        assert_eq!(
+            display_path(&path),
+            Path::new("src/paper.tex").display().to_string()
+        );
+        assert_eq!(display_path(&cwd), ".");
+        set_relative_paths(false);
+        let shown = display_path(&path);
+        set_relative_paths(true);
+        assert_eq!(shown, path.display().to_string());
+    }
+
+    #[test]
+    fn color_choice() {
+        assert!(color_enabled(ColorChoice::Auto, false, true));
+        assert!(!color_enabled(ColorChoice::Auto, false, false));
+        assert!(color_enabled(ColorChoice::Always, false, false));
+        assert!(!color_enabled(ColorChoice::Never, false, true));
+        // NO_COLOR wins, even over --color always
+        for choice in [ColorChoice::Auto, ColorChoice::Always, ColorChoice::Never] {
+            assert!(!color_enabled(choice, true, true));
+        }
+    }
+
+    #[test]
+    fn plain_without_color() {
+        init_color(ColorChoice::Never);
+        assert_eq!(yellow("Warning:"), "Warning:");
+        assert_eq!(red("Failed to build"), "Failed to build");
+    }
+
+    #[test]
+    fn copy_keeps_modification_time() {
+        let dir = TempDir::new("util-test").unwrap();
+        let from = dir.path.join("paper.tex");
+        let to = dir.path.join("copy.tex");
+        let old = SystemTime::now() - std::time::Duration::from_secs(3600);
+        touch(&from, old).unwrap();
+        // Read only, like a source checked out without write access
+        let mut permissions = from.metadata().unwrap().permissions();
+        permissions.set_readonly(true);
+        std::fs::set_permissions(&from, permissions).unwrap();
+        copy_file(&from, &to).unwrap();
+        assert_eq!(
+            to.metadata().unwrap().modified().unwrap(),
+            from.metadata().unwrap().modified().unwrap()
+        );
+    }
+}
@@ -0,0 +1,141 @@
+//
+// view.rs
+// Copyright (C) 2021 matthew <matthew@matthew-ubuntu>
+// Distributed under terms of the MIT license.
+//
+
+use std::{
+    path::{Path, PathBuf},
+    process::Command,
+    str::FromStr,
+};
+
+use crate::{message, util};
+
+/// A location in a source file, as `LINE[:COLUMN]:FILE`
+#[derive(Debug, Clone)]
+pub struct SourcePos {
+    pub line: u32,
+    pub column: u32,
+    pub file: PathBuf,
+}
+
+impl FromStr for SourcePos {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (line, rest) = s
+            .split_once(':')
+            .ok_or_else(|| format!("expected LINE[:COLUMN]:FILE, found `{}`", s))?;
+        let line = line
+            .parse()
+            .map_err(|_| format!("invalid line number `{}`", line))?;
+        // The column is optional, so only take it if it's actually a number
+        let (column, file) = match rest.split_once(':') {
+            Some((column, file)) if column.parse::<u32>().is_ok() => {
+                (column.parse().unwrap(), file)
+            }
+            _ => (0, rest),
+        };
+        if file.is_empty() {
+            return Err("missing file name".into());
+        }
+        Ok(Self {
+            line,
+            column,
+            file: file.into(),
+        })
+    }
+}
+
+/// Ask `synctex view` which page of `pdf` was generated from `pos`
+fn synctex_page(pdf: &Path, pos: &SourcePos) -> Option<u32> {
+    let output = util::output(
+        Command::new("synctex")
+            .arg("view")
+            .arg("-i")
+            .arg(format!(
+                "{}:{}:{}",
+                pos.line,
+                pos.column,
+                pos.file.display()
+            ))
+            .arg("-o")
+            .arg(pdf),
+    )
+    .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .find_map(|l| l.strip_prefix("Page:"))
+        .and_then(|p| p.trim().parse().ok())
+}
+
+/// Find the page to open for `pos`, falling back to the first page
+fn find_page(pdf: &Path, pos: &SourcePos) -> u32 {
+    let synctex = pdf.with_extension("synctex.gz");
+    // The synctex data is written by the engine, so if the source has been edited since, the
+    // line numbers no longer match up
+    let stale = match (util::modified(&synctex), util::modified(&pos.file)) {
+        (Some(synctex), Some(source)) => synctex < source,
+        _ => true,
+    };
+    if stale {
+        message!(
+            "Synctex data for {} is missing or out of date, opening page 1",
+            util::display_path(pdf)
+        );
+        return 1;
+    }
+    synctex_page(pdf, pos).unwrap_or_else(|| {
+        message!(
+            "Synctex couldn't find {}:{}, opening page 1",
+            util::display_path(&pos.file),
+            pos.line
+        );
+        1
+    })
+}
+
+/// Command to open `pdf` at `page`, using the first known viewer that supports it
+fn viewer_command(pdf: &Path, page: Option<u32>) -> Command {
+    if let Some(page) = page {
+        // (viewer, page argument)
+        let viewers: &[(&str, &str)] = &[
+            ("zathura", "--page"),
+            ("okular", "--page"),
+            ("evince", "--page-index"),
+            ("SumatraPDF", "-page"),
+        ];
+        for (viewer, arg) in viewers {
+            if util::find_program(viewer).is_some() {
+                let mut cmd = Command::new(viewer);
+                cmd.arg(arg).arg(page.to_string()).arg(pdf);
+                return cmd;
+            }
+        }
+        message!("No viewer supporting pages found, opening page 1");
+    }
+    let mut cmd = if cfg!(target_os = "macos") {
+        Command::new("open")
+    } else if cfg!(windows) {
+        let mut cmd = Command::new("cmd");
+        cmd.args(["/C", "start", ""]);
+        cmd
+    } else {
+        Command::new("xdg-open")
+    };
+    cmd.arg(pdf);
+    cmd
+}
+
+/// Open `pdf` in a viewer, at the page matching `goto` if provided
+///
+/// The viewer is left running in the background
+pub fn view(pdf: &Path, goto: Option<&SourcePos>) -> std::io::Result<()> {
+    let page = goto.map(|pos| find_page(pdf, pos));
+    util::spawn(&mut viewer_command(pdf, page))?;
+    Ok(())
+}
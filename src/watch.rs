@@ -0,0 +1,106 @@
+//
+// watch.rs
+// Copyright (C) 2021 matthew <matthew@matthew-ubuntu>
+// Distributed under terms of the MIT license.
+//
+
+//! Rebuild whenever an input changes
+//!
+//! Files are polled, so this works the same everywhere, including network drives
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    thread::sleep,
+    time::{Duration, SystemTime},
+};
+
+use crate::{message, recipe::BuildReport, serve::Server, util, Options};
+
+/// How often to check the watched files for changes
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Files from the TeX distribution, which don't need watching
+fn is_distribution_file(path: &Path) -> bool {
+    path.components().any(|c| {
+        c.as_os_str()
+            .to_str()
+            .is_some_and(|c| c.starts_with("texmf") || c == "texlive" || c == "MiKTeX")
+    })
+}
+
+/// Modification times of the files to watch after `report`
+fn watched(options: &Options, report: &BuildReport) -> HashMap<PathBuf, Option<SystemTime>> {
+    let mut paths: Vec<_> = options.files.clone();
+    for input in report.inputs.iter().filter(|p| !is_distribution_file(p)) {
+        if !paths.contains(input) {
+            paths.push(input.clone());
+        }
+    }
+    if paths.len() > options.max_file_watches {
+        message!(
+            "{} {} inputs found, only watching the first {}",
+            util::yellow("Warning:"),
+            paths.len(),
+            options.max_file_watches
+        );
+        paths.truncate(options.max_file_watches);
+    }
+    paths
+        .into_iter()
+        .map(|p| {
+            let time = util::modified(&p);
+            (p, time)
+        })
+        .collect()
+}
+
+/// Whether any of `files` changed since they were recorded
+fn changed(files: &HashMap<PathBuf, Option<SystemTime>>) -> bool {
+    files
+        .iter()
+        .any(|(path, time)| util::modified(path) != *time)
+}
+
+/// Build, and then rebuild every time an input changes
+///
+/// Editors often write several times when saving, so changes only trigger a rebuild once no
+/// more have happened for `--watch-debounce` milliseconds. The outcome of each build is given to
+/// `server`, if there is one.
+pub fn run(options: &Options, server: Option<&Server>) -> ! {
+    let debounce = Duration::from_millis(options.watch_debounce);
+    loop {
+        if let Some(server) = server {
+            server.building();
+        }
+        let started = SystemTime::now();
+        let report = crate::build(options);
+        for diagnostic in report.diagnostics.iter() {
+            message!("{} {}", util::red("Error:"), diagnostic);
+        }
+        let mut files = watched(options, &report);
+        if let Some(server) = server {
+            server.finished(report);
+        }
+        message!("Watching {} files for changes", files.len());
+        // Saved while it was building, so the build may not have seen it
+        let saved_during = files.values().flatten().any(|t| *t > started);
+        while !saved_during && !changed(&files) {
+            sleep(POLL_INTERVAL);
+        }
+        // Wait for the burst of writes to finish
+        loop {
+            files = files
+                .into_keys()
+                .map(|p| {
+                    let time = util::modified(&p);
+                    (p, time)
+                })
+                .collect();
+            sleep(debounce);
+            if !changed(&files) {
+                break;
+            }
+        }
+    }
+}
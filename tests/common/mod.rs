@@ -0,0 +1,78 @@
+//! A project with a fake engine, to run the command line tool in
+
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Stands in for pdflatex, writing the files a real run would for the document it's given
+const FAKE_PDFLATEX: &str = r#"#!/bin/bash
+out=.
+for a; do [ "$prev" = -output-directory ] && out="$a"; prev="$a"; last="$a"; done
+stem="${last%.tex}"
+o="$out/${stem##*/}"
+{ echo "PWD $PWD"; echo "INPUT $last"; echo "OUTPUT $o.aux"; echo "OUTPUT $o.log"; echo "OUTPUT $o.pdf"; } > "$o.fls"
+echo '\relax' > "$o.aux"
+echo "This is fake pdflatex" > "$o.log"
+if grep -q '\\broken' "$last"; then
+  echo "./$last:2: Undefined control sequence."
+  exit 1
+fi
+echo "%PDF-fake" > "$o.pdf"
+"#;
+
+/// A document that builds
+pub const DOCUMENT: &str = "\\documentclass{article}\n\\begin{document}\nHi\n\\end{document}\n";
+
+/// A directory for one test, with the document and a `bin` directory for the programs
+pub struct Project {
+    pub path: PathBuf,
+}
+
+impl Project {
+    pub fn new(name: &str, source: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("latexmk-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(path.join("bin")).unwrap();
+        std::fs::write(path.join("paper.tex"), source).unwrap();
+        // The recipes are run by bash, so it's needed even without an engine
+        std::os::unix::fs::symlink(Path::new("/bin/bash"), path.join("bin/bash")).unwrap();
+        Self { path }
+    }
+
+    pub fn with_engine(self) -> Self {
+        let engine = self.path.join("bin/pdflatex");
+        std::fs::write(&engine, FAKE_PDFLATEX).unwrap();
+        std::fs::set_permissions(&engine, std::fs::Permissions::from_mode(0o755)).unwrap();
+        self
+    }
+
+    /// Exit code of latexmk run in the project
+    ///
+    /// The system programs the fake engine uses are only on the path when it's installed, so a
+    /// real engine can't be found in their place
+    pub fn run(&self, args: &[&str]) -> Option<i32> {
+        let bin = self.path.join("bin");
+        let path = if bin.join("pdflatex").is_file() {
+            std::env::join_paths([bin, "/usr/bin".into(), "/bin".into()]).unwrap()
+        } else {
+            bin.into_os_string()
+        };
+        Command::new(env!("CARGO_BIN_EXE_latexmk"))
+            .args(args)
+            .current_dir(&self.path)
+            .env("PATH", path)
+            .env("NO_COLOR", "1")
+            // Not the user's own settings
+            .env("XDG_CONFIG_HOME", &self.path)
+            .output()
+            .unwrap()
+            .status
+            .code()
+    }
+}
+
+impl Drop for Project {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
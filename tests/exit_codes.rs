@@ -0,0 +1,40 @@
+//! The exit status of the command line tool, for each way a build can end
+
+mod common;
+
+use common::{Project, DOCUMENT};
+
+#[test]
+fn success() {
+    let project = Project::new("success", DOCUMENT).with_engine();
+    assert_eq!(project.run(&["paper.tex"]), Some(0));
+    assert!(project.path.join("paper.pdf").is_file());
+    // Up to date is still a success
+    assert_eq!(project.run(&["paper.tex"]), Some(0));
+}
+
+#[test]
+fn broken_tex() {
+    let source = "\\documentclass{article}\n\\broken\n\\begin{document}\n\\end{document}\n";
+    let project = Project::new("broken", source).with_engine();
+    assert_eq!(project.run(&["paper.tex"]), Some(12));
+}
+
+#[test]
+fn missing_engine() {
+    // Only bash is on the path
+    let project = Project::new("no-engine", DOCUMENT);
+    assert_eq!(project.run(&["paper.tex"]), Some(127));
+}
+
+#[test]
+fn missing_document() {
+    let project = Project::new("no-document", DOCUMENT).with_engine();
+    assert_eq!(project.run(&["other.tex"]), Some(12));
+}
+
+#[test]
+fn invalid_arguments() {
+    let project = Project::new("usage", DOCUMENT).with_engine();
+    assert_eq!(project.run(&["--no-such-option"]), Some(10));
+}
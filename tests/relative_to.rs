@@ -0,0 +1,48 @@
+//! Paths given with `--relative-to` are resolved against that directory, not the one the tool is
+//! started in
+
+mod common;
+
+use common::{Project, DOCUMENT};
+
+/// A project with a `paper.tex` both where the tool is started and in `proj/`
+fn project(name: &str) -> Project {
+    let project = Project::new(name, DOCUMENT).with_engine();
+    std::fs::create_dir(project.path.join("proj")).unwrap();
+    std::fs::write(project.path.join("proj/paper.tex"), DOCUMENT).unwrap();
+    project
+}
+
+#[test]
+fn documents() {
+    let project = project("relative-documents");
+    assert_eq!(
+        project.run(&["--relative-to", "proj", "paper.tex"]),
+        Some(0)
+    );
+    assert!(project.path.join("proj/paper.pdf").is_file());
+    assert!(!project.path.join("paper.pdf").exists());
+}
+
+#[test]
+fn found_documents() {
+    let project = project("relative-found");
+    assert_eq!(project.run(&["--relative-to", "proj"]), Some(0));
+    assert!(project.path.join("proj/paper.pdf").is_file());
+    assert!(!project.path.join("paper.pdf").exists());
+}
+
+#[test]
+fn output_dir() {
+    let project = project("relative-output");
+    let args = ["--relative-to", "proj", "--output-dir", "out", "paper.tex"];
+    assert_eq!(project.run(&args), Some(0));
+    assert!(project.path.join("proj/out/paper.pdf").is_file());
+    assert!(!project.path.join("out").exists());
+}
+
+#[test]
+fn missing_base() {
+    let project = project("relative-missing");
+    assert_eq!(project.run(&["--relative-to", "nowhere"]), Some(10));
+}